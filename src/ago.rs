@@ -58,6 +58,44 @@ fn years_ago(delta: Duration) -> String {
     format!("{n_years:.0} years ago")
 }
 
+/// Reads a phrase like those `from_datetime` writes, such as "3 days ago" or "an hour ago",
+/// back into the time it describes.
+pub fn to_datetime(phrase: &str, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    Some(now - to_duration(phrase)?)
+}
+
+fn to_duration(phrase: &str) -> Option<Duration> {
+    let phrase = phrase.trim().to_lowercase();
+    if phrase == "just now" {
+        return Some(secs(0));
+    }
+
+    let amount = phrase.strip_suffix(" ago")?.trim();
+    if amount == "half an hour" {
+        return Some(mins(30));
+    }
+
+    let (count, unit) = amount.split_once(' ')?;
+    let count: f64 = match count {
+        "a" | "an" => 1.0,
+        count => count.parse().ok().filter(|count: &f64| *count >= 0.0)?,
+    };
+
+    // The same lengths of month and year that `months_ago` and `years_ago` use.
+    let unit_seconds = match unit.trim().trim_end_matches('s') {
+        "sec" | "second" => 1.0,
+        "min" | "minute" => 60.0,
+        "hour" => 60.0 * 60.0,
+        "day" => 24.0 * 60.0 * 60.0,
+        "week" => 7.0 * 24.0 * 60.0 * 60.0,
+        "month" => 30.4 * 24.0 * 60.0 * 60.0,
+        "year" => 365.0 * 24.0 * 60.0 * 60.0,
+        _ => return None,
+    };
+
+    Some(secs((count * unit_seconds).round() as i64))
+}
+
 // write tests to check all cases in the format function
 #[cfg(test)]
 mod tests {
@@ -103,4 +141,26 @@ mod tests {
         assert_eq!(from_duration(days(1000)),         "3 years ago");
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(to_duration("just now"),          Some(secs(0)));
+        assert_eq!(to_duration("5 seconds ago"),     Some(secs(5)));
+        assert_eq!(to_duration("a minute ago"),      Some(mins(1)));
+        assert_eq!(to_duration("half an hour ago"),  Some(mins(30)));
+        assert_eq!(to_duration("An hour ago"),       Some(hours(1)));
+        assert_eq!(to_duration("3 days ago"),        Some(days(3)));
+        assert_eq!(to_duration("2 weeks ago"),       Some(days(14)));
+        assert_eq!(to_duration("a year ago"),        Some(days(365)));
+        assert_eq!(to_duration("3 days"),            None);
+        assert_eq!(to_duration("-3 days ago"),       None);
+        assert_eq!(to_duration("3 fortnights ago"),  None);
+    }
+
+    #[test]
+    fn test_parse_reads_back_what_format_writes() {
+        for duration in [secs(20), mins(15), hours(5), days(3), days(60), days(730)] {
+            assert_eq!(to_duration(&from_duration(duration)).map(from_duration), Some(from_duration(duration)));
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::HashSet;
+use std::io::{IsTerminal, Write};
+
+use colored::Colorize;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::errors::KprError;
+use crate::formatters::TimestampFormatter;
+use crate::query::{Query, WILDCARD};
+use crate::records::Record;
+use crate::search::{make_match_formatter, Index};
+use crate::store::{self, LineNumber};
+
+const HELP: &str = "enter: print  tab: full note  ctrl-e: edit  esc: quit";
+
+/// Why the browser closed.
+pub enum Exit {
+    Quit,
+    /// Print the note's message once the screen is restored.
+    Print(Record),
+    /// Edit the note, then carry on browsing.
+    Edit(Record),
+}
+
+/// The state of `kpr browse`, kept apart from the terminal so that it can be driven by key
+/// presses and rendered to lines of text in tests.
+pub struct Browser {
+    /// Every note, newest first.
+    notes: Vec<(LineNumber, Record)>,
+    index: Index,
+    format_date: TimestampFormatter,
+    query: String,
+    /// The positions in `notes` of those matching the query.
+    shown: Vec<usize>,
+    stems: HashSet<String>,
+    /// Why the query couldn't be used, while it is being typed.
+    error: Option<String>,
+    /// The position in `shown` of the selected note.
+    selected: usize,
+    /// The first note or line on screen.
+    scroll: usize,
+    /// Whether the selected note is shown in full.
+    full: bool,
+    /// How many notes fit on screen, as of the last render.
+    page: usize,
+}
+
+impl Browser {
+    pub fn new(notes: Vec<(LineNumber, Record)>, index: Index, format_date: TimestampFormatter) -> Self {
+        let mut browser = Browser {
+            notes,
+            index,
+            format_date,
+            query: String::new(),
+            shown: Vec::new(),
+            stems: HashSet::new(),
+            error: None,
+            selected: 0,
+            scroll: 0,
+            full: false,
+            page: 1,
+        };
+        browser.filter();
+        browser
+    }
+
+    /// Reads every note and the index.
+    pub fn load(format_date: TimestampFormatter) -> Result<Self, KprError> {
+        let index = Index::load()?;
+        // Not `store::load_records`, as the index knows notes by the line they were written on,
+        // which only `replay` keeps.
+        let mut notes = store::replay(store::load_lines(None));
+        notes.reverse();
+        Ok(Browser::new(notes, index, format_date))
+    }
+
+    /// Reads the notes and index again, after a note was edited, keeping the query and selection.
+    pub fn reload(&mut self) -> Result<(), KprError> {
+        let selected = self.selected;
+        let reloaded = Browser::load(self.format_date)?;
+        self.notes = reloaded.notes;
+        self.index = reloaded.index;
+        self.filter();
+        self.selected = selected.min(self.shown.len().saturating_sub(1));
+        Ok(())
+    }
+
+    fn selected_note(&self) -> Option<&Record> {
+        let &i = self.shown.get(self.selected)?;
+        Some(&self.notes[i].1)
+    }
+
+    /// Shows the notes matching the query, newest first. The last word is matched as the start
+    /// of a word while it's being typed.
+    fn filter(&mut self) {
+        self.selected = 0;
+        self.scroll = 0;
+
+        let query = match Query::parse(&as_you_type(&self.query)) {
+            Ok(Some(query)) => query,
+            Ok(None) => {
+                self.shown = (0..self.notes.len()).collect();
+                self.stems.clear();
+                self.error = None;
+                return;
+            },
+            // Keep showing the last results until the query makes sense again.
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return;
+            },
+        };
+
+        let (lines, stems) = self.index.filter(&query, &self.notes);
+        self.shown = (0..self.notes.len())
+            .filter(|&i| lines.as_ref().is_none_or(|lines| lines.contains(&self.notes[i].0)))
+            .collect();
+        self.stems = stems;
+        self.error = None;
+    }
+
+    /// Acts on a key press, returning why the browser should close, if it should.
+    pub fn handle(&mut self, key: KeyEvent) -> Option<Exit> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if control => return Some(Exit::Quit),
+            KeyCode::Esc if self.full => self.set_full(false),
+            KeyCode::Esc => return Some(Exit::Quit),
+            KeyCode::Enter => return self.selected_note().cloned().map(Exit::Print),
+            KeyCode::Char('e') if control => return self.selected_note().cloned().map(Exit::Edit),
+            KeyCode::Tab => self.set_full(!self.full),
+            KeyCode::Up => self.move_by(-1),
+            KeyCode::Char('p') if control => self.move_by(-1),
+            KeyCode::Down => self.move_by(1),
+            KeyCode::Char('n') if control => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-(self.page as isize)),
+            KeyCode::PageDown => self.move_by(self.page as isize),
+            KeyCode::Char('u') if control => {
+                self.query.clear();
+                self.query_changed();
+            },
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.query_changed();
+            },
+            KeyCode::Char(c) if !control => {
+                self.query.push(c);
+                self.query_changed();
+            },
+            _ => {},
+        }
+        None
+    }
+
+    /// Filters the notes again and goes back to the list, since the note shown in full may no
+    /// longer be the one selected.
+    fn query_changed(&mut self) {
+        self.set_full(false);
+        self.filter();
+    }
+
+    fn set_full(&mut self, full: bool) {
+        self.full = full && self.selected_note().is_some();
+        self.scroll = 0;
+    }
+
+    /// Moves the selection, or scrolls the note when it is shown in full.
+    fn move_by(&mut self, rows: isize) {
+        if self.full {
+            self.scroll = self.scroll.saturating_add_signed(rows);
+            return;
+        }
+        let last = self.shown.len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(rows).min(last);
+    }
+
+    /// The lines to show on a screen `width` columns wide and `height` rows high: the query,
+    /// a status line, and then the notes or the selected note.
+    pub fn render(&mut self, width: usize, height: usize) -> Vec<String> {
+        let status = match &self.error {
+            Some(error) => error.red().to_string(),
+            None => format!("{}/{}  {}", self.shown.len(), self.notes.len(), HELP.bright_black()),
+        };
+        let mut lines = vec![format!("> {}", self.query), status];
+
+        self.page = height.saturating_sub(lines.len()).max(1);
+        if self.full {
+            lines.extend(self.render_full(width));
+        } else {
+            lines.extend(self.render_list(width));
+        }
+        lines.truncate(height);
+        lines
+    }
+
+    fn render_list(&mut self, width: usize) -> Vec<String> {
+        // Scroll just far enough to keep the selection on screen.
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + self.page {
+            self.scroll = self.selected + 1 - self.page;
+        }
+
+        let highlight = make_match_formatter(self.stems.clone(), None);
+        self.shown
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(self.page)
+            .map(|(position, &i)| {
+                let record = &self.notes[i].1;
+                let marker = if position == self.selected { ">" } else { " " };
+                let timestamp = (self.format_date)(record.timestamp);
+                let prefix_width = marker.len() + record.id.len() + timestamp.width() + 6;
+
+                let message = truncate(&record.message.replace('\n', " "), width.saturating_sub(prefix_width));
+                format!("{marker} {}  {}  {}", record.id.yellow(), timestamp.bright_black(), highlight(&message))
+            })
+            .collect()
+    }
+
+    fn render_full(&mut self, width: usize) -> Vec<String> {
+        let Some(record) = self.selected_note() else { return Vec::new() };
+        let highlight = make_match_formatter(self.stems.clone(), None);
+
+        let mut lines = vec![format!("{}  {}", record.id.yellow(), (self.format_date)(record.timestamp).bright_black()), String::new()];
+        lines.extend(textwrap::wrap(&record.message, width.max(1)).iter().map(|line| highlight(&line.to_string())));
+
+        self.scroll = self.scroll.min(lines.len().saturating_sub(1));
+        lines.split_off(self.scroll)
+    }
+}
+
+/// Lets the last word of a query match the start of longer words, as if it ended with a wildcard.
+/// Fields, like `tag:oncall`, are left to match exactly.
+fn as_you_type(query: &str) -> String {
+    let last_word = query.rsplit(char::is_whitespace).next().unwrap_or_default();
+    let is_operator = matches!(last_word, "AND" | "OR" | "NOT") || last_word.starts_with("NEAR");
+    let is_field = last_word.contains(':');
+    if is_operator || is_field || !last_word.ends_with(char::is_alphanumeric) {
+        return query.to_string();
+    }
+    format!("{query}{WILDCARD}")
+}
+
+/// The start of `text`, at most `width` columns wide, ending in `…` if it was cut short.
+/// Characters like `東` take up two columns.
+fn truncate(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut used = 0;
+    for grapheme in text.graphemes(true) {
+        used += grapheme.width();
+        if used + 1 > width {
+            break;
+        }
+        truncated.push_str(grapheme);
+    }
+    truncated.push('…');
+    truncated
+}
+
+/// Shows the browser full screen until it closes.
+pub fn run(browser: &mut Browser) -> Result<Exit, KprError> {
+    let mut stdout = std::io::stdout();
+    if !stdout.is_terminal() {
+        return Err(KprError::new("kpr browse needs a terminal, try kpr list or kpr search instead."));
+    }
+
+    let _screen = Screen::enter(&mut stdout)?;
+    Ok(event_loop(browser, &mut stdout)?)
+}
+
+/// The browser's full screen, which puts the terminal back as it was when dropped, even if
+/// the browser panics.
+struct Screen;
+
+impl Screen {
+    fn enter(stdout: &mut std::io::Stdout) -> Result<Self, std::io::Error> {
+        terminal::enable_raw_mode()?;
+        let screen = Screen;
+        execute!(stdout, EnterAlternateScreen, Hide)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(std::io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn event_loop(browser: &mut Browser, stdout: &mut std::io::Stdout) -> Result<Exit, std::io::Error> {
+    loop {
+        let (width, height) = terminal::size()?;
+        queue!(stdout, Clear(ClearType::All))?;
+        for (row, line) in browser.render(width as usize, height as usize).into_iter().enumerate() {
+            queue!(stdout, MoveTo(0, row as u16), Print(line))?;
+        }
+        stdout.flush()?;
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if let Some(exit) = browser.handle(key) {
+                return Ok(exit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::Entry;
+
+    fn browser_of(messages: &[&str]) -> Browser {
+        let lines: Vec<String> = messages
+            .iter()
+            .map(|message| Entry::Note(Record::create(message.to_string())).to_store())
+            .collect();
+        let index = Index::from_lines(lines.clone());
+        let mut notes = store::replay(lines);
+        notes.reverse();
+        Browser::new(notes, index, |timestamp| timestamp.format("%H:%M").to_string())
+    }
+
+    fn press(browser: &mut Browser, code: KeyCode) -> Option<Exit> {
+        browser.handle(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_text(browser: &mut Browser, text: &str) {
+        for c in text.chars() {
+            press(browser, KeyCode::Char(c));
+        }
+    }
+
+    fn messages(browser: &Browser) -> Vec<&str> {
+        browser.shown.iter().map(|&i| browser.notes[i].1.message.as_str()).collect()
+    }
+
+    #[test]
+    fn test_notes_are_newest_first_and_filter_as_you_type() {
+        let mut browser = browser_of(&["deploy api", "restart kubernetes", "deploy web"]);
+        assert_eq!(messages(&browser), vec!["deploy web", "restart kubernetes", "deploy api"]);
+
+        type_text(&mut browser, "kube");
+        assert_eq!(messages(&browser), vec!["restart kubernetes"]);
+
+        for _ in 0..4 {
+            press(&mut browser, KeyCode::Backspace);
+        }
+        type_text(&mut browser, "deploy -web");
+        assert_eq!(messages(&browser), vec!["deploy api"]);
+    }
+
+    #[test]
+    fn test_whole_words_stay_shown_while_typed() {
+        let mut browser = browser_of(&["restart kubernetes", "deployment of api", "lunch"]);
+        for (word, message) in [("kubernetes", "restart kubernetes"), ("deployment", "deployment of api")] {
+            let mut typed = String::new();
+            for c in word.chars() {
+                typed.push(c);
+                press(&mut browser, KeyCode::Char(c));
+                assert_eq!(messages(&browser), vec![message], "after typing {typed:?}");
+            }
+            for _ in 0..word.len() {
+                press(&mut browser, KeyCode::Backspace);
+            }
+        }
+    }
+
+    #[test]
+    fn test_typos_are_not_matched_fuzzily() {
+        let mut browser = browser_of(&["restart kubernetes"]);
+        type_text(&mut browser, "kubrenetes ");
+        assert!(messages(&browser).is_empty());
+    }
+
+    #[test]
+    fn test_unfinished_queries_keep_the_last_results() {
+        let mut browser = browser_of(&["deploy api", "restart kubernetes"]);
+        type_text(&mut browser, "(deploy");
+        assert!(browser.error.is_some());
+        assert_eq!(messages(&browser), vec!["restart kubernetes", "deploy api"]);
+
+        type_text(&mut browser, ")");
+        assert!(browser.error.is_none());
+        assert_eq!(messages(&browser), vec!["deploy api"]);
+    }
+
+    #[test]
+    fn test_selection_stays_on_the_list() {
+        let mut browser = browser_of(&["one", "two", "three"]);
+        press(&mut browser, KeyCode::Up);
+        assert_eq!(browser.selected, 0);
+        for _ in 0..5 {
+            press(&mut browser, KeyCode::Down);
+        }
+        assert_eq!(browser.selected, 2);
+        assert!(matches!(press(&mut browser, KeyCode::Enter), Some(Exit::Print(note)) if note.message == "one"));
+    }
+
+    #[test]
+    fn test_actions_on_the_selected_note() {
+        let mut browser = browser_of(&["deploy api"]);
+        let edit = browser.handle(KeyEvent::new(KeyCode::Char('e'), KeyModifiers::CONTROL));
+        assert!(matches!(edit, Some(Exit::Edit(note)) if note.message == "deploy api"));
+
+        press(&mut browser, KeyCode::Tab);
+        assert!(browser.full);
+        assert!(press(&mut browser, KeyCode::Esc).is_none());
+        assert!(!browser.full);
+        assert!(matches!(press(&mut browser, KeyCode::Esc), Some(Exit::Quit)));
+    }
+
+    #[test]
+    fn test_typing_leaves_the_full_view() {
+        let mut browser = browser_of(&["deploy api", "restart kubernetes"]);
+        press(&mut browser, KeyCode::Tab);
+        assert!(browser.full);
+
+        type_text(&mut browser, "deploy");
+        assert!(!browser.full);
+        assert_eq!(messages(&browser), vec!["deploy api"]);
+    }
+
+    #[test]
+    fn test_render_scrolls_to_the_selection() {
+        let mut browser = browser_of(&["one", "two", "three", "four", "five"]);
+        assert_eq!(browser.render(80, 4).len(), 4);
+
+        for _ in 0..3 {
+            press(&mut browser, KeyCode::Down);
+        }
+        let lines = browser.render(80, 4);
+        assert_eq!(browser.scroll, 2);
+        assert!(lines[2].starts_with(' ') && lines[2].contains("three"));
+        assert!(lines[3].starts_with('>') && lines[3].contains("two"));
+    }
+
+    #[test]
+    fn test_full_note_is_wrapped() {
+        let mut browser = browser_of(&["word ".repeat(30).trim()]);
+        press(&mut browser, KeyCode::Tab);
+        let lines = browser.render(40, 20);
+        assert!(lines.len() > 5);
+        assert!(lines[4..].iter().all(|line| line.width() <= 40));
+    }
+
+    #[test]
+    fn test_as_you_type() {
+        assert_eq!(as_you_type("deploy kube"), "deploy kube*");
+        assert_eq!(as_you_type("deploy "), "deploy ");
+        assert_eq!(as_you_type("deploy AND"), "deploy AND");
+        assert_eq!(as_you_type("deploy tag:api"), "deploy tag:api");
+        assert_eq!(as_you_type("\"disk ful"), "\"disk ful*");
+        assert_eq!(as_you_type(""), "");
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("deploy api", 20), "deploy api");
+        assert_eq!(truncate("deploy api", 6), "deplo…");
+        assert_eq!(truncate("東京都に行く", 12), "東京都に行く");
+        assert_eq!(truncate("東京都に行く", 8), "東京都…");
+        assert_eq!(truncate("東京都に行く", 7), "東京都…");
+        assert_eq!(truncate("東京都に行く", 6), "東京…");
+    }
+}
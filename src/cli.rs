@@ -1,12 +1,39 @@
 
-use clap::{Args, Parser, Subcommand, arg, ValueEnum};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use clap::{ArgGroup, Args, Command, CommandFactory, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ColorMode, Key};
+use crate::dates::{parse_period, Period};
+use crate::errors::KprError;
+use crate::records::normalize_tag;
+use crate::saved::SavedSearch;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Cli {
+    /// the directory kpr keeps its notebooks in [default: ~/.kpr]
+    #[arg(long, global = true, env = "KPR_HOME", value_name = "DIR")]
+    pub store: Option<PathBuf>,
+
+    /// the notebook to use [default: default]
+    #[arg(short = 'b', long, global = true, value_name = "NAME")]
+    pub notebook: Option<String>,
+
+    /// when to colour output [default: auto]
+    #[arg(long, global = true, value_enum, value_name = "WHEN")]
+    pub color: Option<ColorMode>,
+
+    /// the column at which messages wrap [default: 80]
+    #[arg(long, global = true, value_name = "COLUMNS")]
+    pub width: Option<usize>,
+
     #[command(subcommand)]
-    command: Commands,
+    pub command: Commands,
 }
 
 #[derive(Subcommand)]
@@ -14,7 +41,13 @@ pub enum Commands {
     /// Store a message for later
     #[command(alias("add"))]
     #[command(alias("kp"))]
-    Keep { message: Vec<String> },
+    Keep {
+        message: Vec<String>,
+
+        /// write the message in $VISUAL or $EDITOR (the default when there is no message and no piped input)
+        #[arg(short, long)]
+        editor: bool,
+    },
     
     /// List your messages
     #[command(alias("ls"))]
@@ -23,46 +56,505 @@ pub enum Commands {
     /// Search your messages
     Search(SearchArgs),
 
+    /// Find the messages most like one of your messages
+    Related {
+        /// the ID (or the start of the ID) of the message
+        note: String,
+
+        /// the max number of results to return [default: 10]
+        #[arg(short)]
+        n: Option<usize>,
+
+        /// the date format to use for timestamps [default: ago]
+        #[arg(short, long, value_enum)]
+        date_format: Option<DateFormat>,
+
+        /// show whole messages, rather than the words around the shared ones in long ones
+        #[arg(long)]
+        full: bool,
+    },
+
+    /// List the searches saved with `kpr search --save`
+    Saved,
+
+    /// Run a saved search
+    Run {
+        /// the name the search was saved as
+        name: String,
+    },
+
+    /// Change one of your messages
+    Edit {
+        /// the ID (or the start of the ID) of the message to change
+        note: String,
+        message: Vec<String>,
+
+        /// edit the message in $VISUAL or $EDITOR (the default when there is no message and no piped input)
+        #[arg(short, long)]
+        editor: bool,
+    },
+
+    /// Delete one of your messages
+    #[command(alias("delete"))]
+    Rm {
+        /// the ID (or the start of the ID) of the message to delete
+        note: String,
+    },
+
+    /// Rewrite the store without edited and deleted messages
+    Compact,
+
+    /// Upgrade the store to the current format, keeping a backup
+    Migrate,
+
+    /// List your #tags and how many messages have each
+    Tags,
+
+    /// List your notebooks
+    Notebooks,
+
+    /// Browse and search your messages interactively
+    Browse,
+
+    /// Show or change your settings
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
     // re-index the stored messages
     Index,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub enum DateFormat {
     Ago,
-    ISO,
+    Iso,
     Epoch,
     EpochMs,
     Human,
 }
 
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Show the value of a setting
+    Get { key: Key },
+
+    /// Change a setting in config.toml
+    Set {
+        key: Key,
+        value: String,
+
+        /// change it for the notebook chosen with -b only
+        #[arg(short, long)]
+        local: bool,
+    },
+
+    /// Remove a setting from config.toml, so the default is used
+    Unset {
+        key: Key,
+
+        /// remove it from the config of the notebook chosen with -b
+        #[arg(short, long)]
+        local: bool,
+    },
+
+    /// Show every setting and where its value comes from
+    #[command(alias("ls"))]
+    List,
+}
+
 #[derive(Args)]
 pub struct ListArgs {
-    // the max number of results to return
-    #[arg(short, default_value_t = 10)]
-    pub n: usize,
+    /// only show messages matching these words and fields, like tag:oncall after:yesterday len:>200
+    #[arg(allow_hyphen_values = true)]
+    pub query: Vec<String>,
 
-    // the date format to use for timestamps
-    #[arg(short, long, value_enum, default_value_t = DateFormat::Ago)]
+    /// the max number of results to return [default: 10]
+    #[arg(short)]
+    pub n: Option<usize>,
+
+    /// the date format to use for timestamps [default: ago]
     #[arg(short, long, value_enum)]
-    pub date_format: DateFormat,
+    pub date_format: Option<DateFormat>,
+
+    /// only show messages with this tag (repeat for messages with all of several tags)
+    #[arg(short, long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+
+    #[command(flatten)]
+    pub dates: DateArgs,
 }
 
 // struct of SearchArgs
 #[derive(Args)]
+#[command(group(ArgGroup::new("pattern").args(["regex", "literal"])))]
 pub struct SearchArgs {
+    /// words to search for, combined with AND, OR, NOT (or &, |, -word), parentheses and "quotes",
+    /// and fields like tag:oncall, date:2026-10, before:WHEN, after:WHEN, id:ID or len:>200
+    #[arg(allow_hyphen_values = true)]
     pub query: Vec<String>,
 
-    // the max number of results to return
-    #[arg(short, default_value_t = 10)]
-    pub n: usize,
+    /// the max number of results to return [default: 10]
+    #[arg(short)]
+    pub n: Option<usize>,
 
-    // the date format to use for timestamps
-    #[arg(short, long, value_enum, default_value_t = DateFormat::Ago)]
+    /// the date format to use for timestamps [default: ago]
     #[arg(short, long, value_enum)]
-    pub date_format: DateFormat,
+    pub date_format: Option<DateFormat>,
+
+    /// only show messages with this tag (repeat for messages with all of several tags)
+    #[arg(short, long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+
+    /// how to order the results
+    #[arg(short, long, value_enum, default_value_t = SortOrder::Relevance)]
+    pub sort: SortOrder,
+
+    /// favour newer messages when sorting by relevance
+    #[arg(short, long)]
+    pub recent: bool,
+
+    /// also match words with typos, even when some match exactly
+    #[arg(short, long)]
+    pub fuzzy: bool,
+
+    /// match the query as a regular expression against whole messages, newest first unless --sort oldest
+    #[arg(short = 'E', long, conflicts_with_all = ["literal", "fuzzy", "recent"])]
+    pub regex: bool,
+
+    /// match the query exactly as written, punctuation and all, newest first unless --sort oldest
+    #[arg(short = 'F', long, conflicts_with_all = ["fuzzy", "recent"])]
+    pub literal: bool,
+
+    /// ignore upper and lower case with --regex or --literal
+    #[arg(short, long, requires = "pattern")]
+    pub ignore_case: bool,
+
+    /// show whole messages, rather than the words around the matches in long ones
+    #[arg(long)]
+    pub full: bool,
+
+    /// save this search as NAME, to run again with `kpr run NAME`
+    #[arg(long, value_name = "NAME")]
+    pub save: Option<String>,
+
+    #[command(flatten)]
+    pub dates: DateArgs,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum SortOrder {
+    /// the best matches first
+    Relevance,
+    Newest,
+    Oldest,
 }
 
-pub fn get_cmd() -> Commands {
-    Cli::parse().command
+/// Limits `list` and `search` to messages kept at certain times.
+#[derive(Args, Clone, Default)]
+pub struct DateArgs {
+    /// only show messages kept since WHEN, like 2023-04-08, "3 days ago", "yesterday" or "last week"
+    #[arg(long, value_name = "WHEN", value_parser = parse_period)]
+    pub since: Option<Period>,
+
+    /// only show messages kept until the end of WHEN
+    #[arg(long, value_name = "WHEN", value_parser = parse_period)]
+    pub until: Option<Period>,
+
+    /// only show messages kept on the day (or week, month...) of WHEN, like "last tuesday"
+    #[arg(long, value_name = "WHEN", value_parser = parse_period)]
+    pub on: Option<Period>,
+}
+
+impl DateArgs {
+    pub fn is_empty(&self) -> bool {
+        self.since.is_none() && self.until.is_none() && self.on.is_none()
+    }
+
+    pub fn contains(&self, timestamp: DateTime<Local>) -> bool {
+        let on = self.on.map(Period::whole_days);
+        self.since.is_none_or(|since| since.started_by(timestamp))
+            && self.until.is_none_or(|until| until.not_over_by(timestamp))
+            && on.is_none_or(|on| on.started_by(timestamp) && on.not_over_by(timestamp))
+    }
+
+    /// The earliest time a message can have been kept and still be shown.
+    pub fn earliest(&self) -> Option<DateTime<Local>> {
+        let on = self.on.map(|on| on.whole_days().start);
+        self.since.map(|since| since.start).into_iter().chain(on).max()
+    }
+}
+
+/// Tags are letters, numbers, `-` and `_`, after an optional `#`.
+fn parse_tag(tag: &str) -> Result<String, String> {
+    let normalized = normalize_tag(tag);
+    let is_tag = tag.trim_start_matches('#').chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if normalized.is_empty() || !is_tag {
+        return Err(format!("'{tag}' is not a tag"));
+    }
+    Ok(normalized)
+}
+
+pub fn get_cli() -> Cli {
+    Cli::parse_from(hoist_search_options(std::env::args_os().collect()))
+}
+
+/// Whether `token` is exactly one of `command`'s options, and if so whether it takes a value.
+/// Tokens like `-dev` are left alone, so that negated search words aren't mistaken for `-d ev`.
+fn option_takes_value(command: &Command, token: &str) -> Option<bool> {
+    let (name, inline_value) = match token.strip_prefix("--") {
+        Some(long) => match long.split_once('=') {
+            Some((name, _)) => (name, true),
+            None => (long, false),
+        },
+        None => (token, false),
+    };
+
+    let arg = command.get_arguments().find(|arg| {
+        if token.starts_with("--") {
+            arg.get_long() == Some(name)
+        } else {
+            arg.get_short().is_some_and(|short| token == format!("-{short}"))
+        }
+    })?;
+
+    Some(!inline_value && arg.get_action().takes_values())
+}
+
+/// Splits a short option with its value attached, like `-n5` or `-diso`, into the option and its
+/// value, since clap would otherwise read it as a word of the query. Only options taking a count
+/// or one of a fixed list of values are split, and only when the value is valid, so that negated
+/// words like `-testing` or `-dev` aren't mistaken for `-t esting` or `-d ev`. Tags can be any
+/// word, so `-t` needs its value apart.
+fn split_attached_value(command: &Command, token: &str) -> Option<[String; 2]> {
+    let mut chars = token.strip_prefix('-')?.chars();
+    let short = chars.next()?;
+    let value = chars.as_str();
+    let arg = command.get_arguments().find(|arg| arg.get_short() == Some(short))?;
+
+    let is_count = value.chars().all(|c| c.is_ascii_digit());
+    let is_listed = !arg.get_possible_values().is_empty();
+    let is_valid = || Command::new("kpr").arg(arg.clone()).try_get_matches_from(["kpr", token]).is_ok();
+    (!value.is_empty() && arg.get_action().takes_values() && (is_count || is_listed) && is_valid())
+        .then(|| [format!("-{short}"), value.to_string()])
+}
+
+/// `kpr search deploy -staging` needs the query to take words starting with `-`, but then clap
+/// would read any options after the query as more words. So the search options are moved in
+/// front of the query, and `kpr search deploy -n 5` still works. `list` takes a query too.
+fn hoist_search_options(args: Vec<OsString>) -> Vec<OsString> {
+    let Some((position, options, query)) = split_search(&args) else { return args };
+
+    let mut hoisted = args[..=position].to_vec();
+    hoisted.extend(options);
+    hoisted.extend(query);
+    hoisted
+}
+
+/// Finds `search` (or `list`) in the arguments, and splits the arguments after it into its
+/// options and its query. Everything from a `--` on is the query.
+fn split_search(args: &[OsString]) -> Option<(usize, Vec<OsString>, Vec<OsString>)> {
+    let mut command = Cli::command();
+    command.build();
+
+    // Find `search`, stepping over any global options before it.
+    let mut position = 1;
+    loop {
+        let token = args.get(position)?.to_str()?;
+        if token == "--" {
+            return None;
+        }
+        if token.starts_with('-') {
+            position += if option_takes_value(&command, token) == Some(true) { 2 } else { 1 };
+            continue;
+        }
+        break;
+    }
+
+    let name = args[position].to_str()?;
+    let search = command.find_subcommand(name).filter(|found| matches!(found.get_name(), "search" | "list"))?;
+    let mut options = Vec::new();
+    let mut query = Vec::new();
+    let mut rest = args[position + 1..].iter().cloned();
+
+    while let Some(arg) = rest.next() {
+        let token = arg.to_str().unwrap_or_default();
+        if token == "--" {
+            query.push(arg);
+            break;
+        }
+        if let Some(split) = split_attached_value(search, token) {
+            options.extend(split.map(OsString::from));
+            continue;
+        }
+        match option_takes_value(search, token) {
+            Some(takes_value) => {
+                options.push(arg);
+                if takes_value {
+                    options.extend(rest.next());
+                }
+            },
+            None => query.push(arg),
+        }
+    }
+    query.extend(rest);
+    Some((position, options, query))
+}
+
+/// The search to keep for `kpr search --save`: its options as they were typed but without
+/// `--save` itself, the words of the `query` that ran, and the `notebook` it searched.
+pub fn search_to_save(args: Vec<OsString>, query: Vec<String>, notebook: &str) -> SavedSearch {
+    let options = split_search(&args).map(|(_, options, _)| options).unwrap_or_default();
+    let strings = |args: Vec<OsString>| -> Vec<String> {
+        args.into_iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    };
+
+    let mut kept = Vec::new();
+    let mut options = strings(options).into_iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--save" => { options.next(); },
+            _ if option.starts_with("--save=") => {},
+            _ => kept.push(option),
+        }
+    }
+    SavedSearch { query, options: kept, notebook: notebook.to_string() }
+}
+
+/// Reads a saved search's options and query as `kpr search` would.
+pub fn search_args_of(saved: &SavedSearch) -> Result<SearchArgs, KprError> {
+    let mut args: Vec<String> = vec!["kpr".to_string(), "search".to_string()];
+    args.extend(saved.options.iter().cloned());
+    args.push("--".to_string());
+    args.extend(saved.query.iter().cloned());
+
+    match Cli::try_parse_from(args) {
+        Ok(Cli { command: Commands::Search(args), .. }) => Ok(args),
+        Ok(_) => unreachable!("A search is parsed as a search"),
+        Err(e) => Err(KprError::new(&format!("Could not run the saved search: {}", e.kind()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hoist(args: &str) -> String {
+        let args = args.split(' ').map(OsString::from).collect();
+        let hoisted: Vec<String> = hoist_search_options(args)
+            .into_iter()
+            .map(|arg| arg.into_string().expect("Args are unicode"))
+            .collect();
+        hoisted.join(" ")
+    }
+
+    #[test]
+    fn test_search_options_are_moved_before_the_query() {
+        assert_eq!(hoist("kpr search deploy -staging -n 5"), "kpr search -n 5 deploy -staging");
+        assert_eq!(hoist("kpr -b work search -dev --tag=api x"), "kpr -b work search --tag=api -dev x");
+        assert_eq!(hoist("kpr search deploy -- -n 5"), "kpr search deploy -- -n 5");
+        assert_eq!(hoist("kpr list -n 5"), "kpr list -n 5");
+        assert_eq!(hoist("kpr ls -tag:api -n 5"), "kpr ls -n 5 -tag:api");
+        assert_eq!(hoist("kpr tags -n"), "kpr tags -n");
+        assert_eq!(hoist("kpr search -fix.* -E -i"), "kpr search -E -i -fix.*");
+    }
+
+    #[test]
+    fn test_counts_and_listed_values_can_be_attached() {
+        assert_eq!(hoist("kpr search deploy -n5"), "kpr search -n 5 deploy");
+        assert_eq!(hoist("kpr search deploy -tapi -nginx -staging"), "kpr search deploy -tapi -nginx -staging");
+        assert_eq!(hoist("kpr search deploy -diso -soldest -n5"), "kpr search -d iso -s oldest -n 5 deploy");
+        assert_eq!(hoist("kpr search deploy -dev -sql"), "kpr search deploy -dev -sql");
+        assert_eq!(hoist("kpr list -n5 -dev"), "kpr list -n 5 -dev");
+        assert_eq!(hoist("kpr -bwork search x -n5"), "kpr -bwork search -n 5 x");
+
+        match Cli::parse_from(hoist_search_options("kpr search deploy -n5 -t api".split(' ').map(OsString::from).collect())).command {
+            Commands::Search(args) => {
+                assert_eq!(args.query, vec!["deploy"]);
+                assert_eq!((args.n, args.tags), (Some(5), vec!["api".to_string()]));
+            },
+            _ => panic!("Expected a search"),
+        }
+        match Cli::parse_from(hoist_search_options("kpr list -n5 -dev".split(' ').map(OsString::from).collect())).command {
+            Commands::List(args) => assert_eq!((args.n, args.query), (Some(5), vec!["-dev".to_string()])),
+            _ => panic!("Expected a list"),
+        }
+        match Cli::parse_from(hoist_search_options("kpr list -diso -n 2".split(' ').map(OsString::from).collect())).command {
+            Commands::List(args) => {
+                assert_eq!((args.date_format, args.n), (Some(DateFormat::Iso), Some(2)));
+                assert!(args.query.is_empty());
+            },
+            _ => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    fn test_negated_words_are_not_options_with_values_attached() {
+        assert_eq!(hoist("kpr search deploy -testing"), "kpr search deploy -testing");
+
+        match Cli::parse_from(hoist_search_options("kpr search deploy -testing".split(' ').map(OsString::from).collect())).command {
+            Commands::Search(args) => {
+                assert_eq!(args.query, vec!["deploy", "-testing"]);
+                assert!(args.tags.is_empty());
+            },
+            _ => panic!("Expected a search"),
+        }
+
+        assert_eq!(hoist("kpr list -testing -n 5"), "kpr list -n 5 -testing");
+        assert_eq!(hoist("kpr ls -tag:api -diso"), "kpr ls -d iso -tag:api");
+        match Cli::parse_from(hoist_search_options("kpr list -testing".split(' ').map(OsString::from).collect())).command {
+            Commands::List(args) => assert_eq!((args.query, args.tags), (vec!["-testing".to_string()], Vec::new())),
+            _ => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    fn test_saved_searches_keep_what_was_typed() {
+        let args = "kpr -b work search deploy -staging --save deploys --since yesterday".split(' ').map(OsString::from).collect();
+        let saved = search_to_save(args, vec!["deploy".to_string(), "-staging".to_string()], "work");
+        assert_eq!(saved.query, vec!["deploy", "-staging"]);
+        assert_eq!(saved.options, vec!["--since", "yesterday"]);
+        assert_eq!(saved.notebook, "work");
+
+        let args = search_args_of(&saved).expect("Could not parse the saved search");
+        assert_eq!(args.query, vec!["deploy", "-staging"]);
+        assert!(args.dates.since.is_some() && args.save.is_none());
+
+        let saved = search_to_save("kpr search --save=x -n 5 -- -n".split(' ').map(OsString::from).collect(), vec!["-n".to_string()], "default");
+        assert_eq!(saved.query, vec!["-n"]);
+        assert_eq!(saved.options, vec!["-n", "5"]);
+        assert_eq!(search_args_of(&saved).expect("Could not parse the saved search").query, vec!["-n"]);
+
+        let spaced = vec![OsString::from("kpr"), "search".into(), "-E".into(), "a  b".into(), "--save".into(), "x".into()];
+        let saved = search_to_save(spaced, vec!["a  b".to_string()], "default");
+        assert_eq!(saved.query, vec!["a  b"]);
+        assert_eq!(search_args_of(&saved).expect("Could not parse the saved search").query, vec!["a  b"]);
+
+        // A query typed at the prompt isn't in the arguments, but is what ran.
+        let saved = search_to_save("kpr search --save x -n 5".split(' ').map(OsString::from).collect(), vec!["deploy".to_string()], "default");
+        assert_eq!((saved.query, saved.options), (vec!["deploy".to_string()], vec!["-n".to_string(), "5".to_string()]));
+
+        let broken = SavedSearch { query: Vec::new(), options: vec!["-n".to_string(), "lots".to_string()], notebook: "default".to_string() };
+        assert!(search_args_of(&broken).is_err());
+    }
+
+    #[test]
+    fn test_ignore_case_needs_a_pattern() {
+        assert!(Cli::try_parse_from(["kpr", "search", "-i", "deploy"]).is_err());
+        assert!(Cli::try_parse_from(["kpr", "search", "-i", "-E", "deploy"]).is_ok());
+        assert!(Cli::try_parse_from(["kpr", "search", "-F", "-i", "deploy"]).is_ok());
+    }
+
+    #[test]
+    fn test_negated_words_parse_as_the_query() {
+        let args: Vec<OsString> = "kpr search deploy -staging -n 5".split(' ').map(OsString::from).collect();
+        let cli = Cli::parse_from(hoist_search_options(args));
+        match cli.command {
+            Commands::Search(args) => {
+                assert_eq!(args.query, vec!["deploy", "-staging"]);
+                assert_eq!(args.n, Some(5));
+            },
+            _ => panic!("Expected a search"),
+        }
+    }
 }
@@ -0,0 +1,350 @@
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::DateFormat;
+use crate::errors::KprError;
+use crate::notebooks::DEFAULT_NOTEBOOK;
+use crate::search::Language;
+
+pub const CONFIG_FILENAME: &str = "config.toml";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /// colour output when writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// The settings which can be kept in `config.toml`.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+#[value(rename_all = "snake_case")]
+pub enum Key {
+    /// how many results `list` and `search` show
+    Count,
+    /// how `list` and `search` show timestamps
+    DateFormat,
+    /// the column at which messages wrap
+    WrapWidth,
+    /// when to colour output: auto, always or never
+    Color,
+    /// the notebook to use when -b is not given
+    Notebook,
+    /// the language used to stem words for search
+    Language,
+    /// how many words of a long message `search` shows around the matches
+    SnippetWidth,
+}
+
+impl Key {
+    pub fn name(self) -> String {
+        self.to_possible_value().expect("Every key has a name").get_name().to_string()
+    }
+
+    /// Each setting can also be given in an environment variable, like `KPR_DATE_FORMAT`.
+    pub fn env_var(self) -> String {
+        format!("KPR_{}", self.name().to_uppercase())
+    }
+}
+
+/// The contents of `config.toml`. Anything left out falls back to the built-in default.
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub count: Option<usize>,
+    pub date_format: Option<DateFormat>,
+    pub wrap_width: Option<usize>,
+    pub color: Option<ColorMode>,
+    pub notebook: Option<String>,
+    pub language: Option<Language>,
+    pub snippet_width: Option<usize>,
+}
+
+impl Config {
+    pub fn path(root: &Path) -> PathBuf {
+        root.join(CONFIG_FILENAME)
+    }
+
+    pub fn load(root: &Path) -> Result<Self, KprError> {
+        let filepath = Self::path(root);
+        let contents = match std::fs::read_to_string(&filepath) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        toml::from_str(&contents)
+            .map_err(|e| KprError::new(&format!("Could not read {}: {e}", filepath.display())))
+    }
+
+    pub fn save(&self, root: &Path) -> Result<(), KprError> {
+        let contents = toml::to_string(self)
+            .map_err(|e| KprError::new(&format!("Could not write config: {e}")))?;
+        std::fs::create_dir_all(root)?;
+        std::fs::write(Self::path(root), contents)?;
+        Ok(())
+    }
+
+    /// These settings, with any that `notebook`'s own config sets taking their place.
+    /// Which notebook to use can only be chosen in the data directory's config.
+    pub fn with_notebook(self, notebook: Config) -> Config {
+        Config {
+            count: notebook.count.or(self.count),
+            date_format: notebook.date_format.or(self.date_format),
+            wrap_width: notebook.wrap_width.or(self.wrap_width),
+            color: notebook.color.or(self.color),
+            notebook: self.notebook,
+            language: notebook.language.or(self.language),
+            snippet_width: notebook.snippet_width.or(self.snippet_width),
+        }
+    }
+
+    pub fn get(&self, key: Key) -> Option<String> {
+        match key {
+            Key::Count => self.count.map(|count| count.to_string()),
+            Key::DateFormat => self.date_format.map(value_name),
+            Key::WrapWidth => self.wrap_width.map(|width| width.to_string()),
+            Key::Color => self.color.map(value_name),
+            Key::Notebook => self.notebook.clone(),
+            Key::Language => self.language.map(value_name),
+            Key::SnippetWidth => self.snippet_width.map(|width| width.to_string()),
+        }
+    }
+
+    /// Sets `key` to `value`, or removes it from the config when `value` is `None`.
+    pub fn set(&mut self, key: Key, value: Option<&str>) -> Result<(), KprError> {
+        let value = match value {
+            Some(value) => value,
+            None => {
+                match key {
+                    Key::Count => self.count = None,
+                    Key::DateFormat => self.date_format = None,
+                    Key::WrapWidth => self.wrap_width = None,
+                    Key::Color => self.color = None,
+                    Key::Notebook => self.notebook = None,
+                    Key::Language => self.language = None,
+                    Key::SnippetWidth => self.snippet_width = None,
+                }
+                return Ok(());
+            },
+        };
+
+        match key {
+            Key::Count => self.count = Some(parse_number(key, value)?),
+            Key::DateFormat => self.date_format = Some(parse_choice(key, value)?),
+            Key::WrapWidth => self.wrap_width = Some(parse_number(key, value)?),
+            Key::Color => self.color = Some(parse_choice(key, value)?),
+            Key::Notebook => self.notebook = Some(parse_text(key, value)?),
+            Key::Language => self.language = Some(parse_choice(key, value)?),
+            Key::SnippetWidth => self.snippet_width = Some(parse_number(key, value)?),
+        }
+        Ok(())
+    }
+}
+
+/// The settings in effect, after taking environment variables, the config file and defaults into
+/// account. Command line flags are applied on top of these by each command.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub count: usize,
+    pub date_format: DateFormat,
+    pub wrap_width: usize,
+    pub color: ColorMode,
+    pub notebook: String,
+    pub language: Language,
+    pub snippet_width: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            count: 10,
+            date_format: DateFormat::Ago,
+            wrap_width: 80,
+            color: ColorMode::Auto,
+            notebook: DEFAULT_NOTEBOOK.to_string(),
+            language: Language::English,
+            snippet_width: 20,
+        }
+    }
+}
+
+impl Settings {
+    pub fn resolve(config: &Config) -> Result<Self, KprError> {
+        let defaults = Settings::default();
+        Ok(Settings {
+            count: resolve(Key::Count, config.count, parse_number)?.unwrap_or(defaults.count),
+            date_format: resolve(Key::DateFormat, config.date_format, parse_choice)?.unwrap_or(defaults.date_format),
+            wrap_width: resolve(Key::WrapWidth, config.wrap_width, parse_number)?.unwrap_or(defaults.wrap_width),
+            color: resolve(Key::Color, config.color, parse_choice)?.unwrap_or(defaults.color),
+            notebook: resolve(Key::Notebook, config.notebook.clone(), parse_text)?.unwrap_or(defaults.notebook),
+            language: resolve(Key::Language, config.language, parse_choice)?.unwrap_or(defaults.language),
+            snippet_width: resolve(Key::SnippetWidth, config.snippet_width, parse_number)?.unwrap_or(defaults.snippet_width),
+        })
+    }
+
+    pub fn get(&self, key: Key) -> String {
+        match key {
+            Key::Count => self.count.to_string(),
+            Key::DateFormat => value_name(self.date_format),
+            Key::WrapWidth => self.wrap_width.to_string(),
+            Key::Color => value_name(self.color),
+            Key::Notebook => self.notebook.clone(),
+            Key::Language => value_name(self.language),
+            Key::SnippetWidth => self.snippet_width.to_string(),
+        }
+    }
+
+    /// Makes these the settings for the rest of the process.
+    pub fn apply(self) {
+        match self.color {
+            ColorMode::Always => colored::control::set_override(true),
+            ColorMode::Never => colored::control::set_override(false),
+            ColorMode::Auto => if !std::io::stdout().is_terminal() { colored::control::set_override(false) },
+        }
+        let _ = SETTINGS.set(self);
+    }
+}
+
+/// The settings in effect, or the defaults if none have been applied.
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// Where the value of a setting comes from, for `kpr config list`. `flagged` are the settings
+/// given as flags, like `--width`. `notebook` is the selected notebook's own config, unless that
+/// is the default notebook, whose config is `config`.
+pub fn source(key: Key, flagged: &[Key], config: &Config, notebook: Option<&Config>) -> String {
+    if flagged.contains(&key) {
+        return "flag".to_string();
+    }
+    if std::env::var(key.env_var()).is_ok() {
+        return key.env_var();
+    }
+    if key != Key::Notebook && notebook.is_some_and(|notebook| notebook.get(key).is_some()) {
+        return format!("notebook {CONFIG_FILENAME}");
+    }
+    if config.get(key).is_some() {
+        return CONFIG_FILENAME.to_string();
+    }
+    "default".to_string()
+}
+
+type Parser<T> = fn(Key, &str) -> Result<T, KprError>;
+
+/// The environment variable for `key` wins over the config file.
+fn resolve<T>(key: Key, from_config: Option<T>, parse: Parser<T>) -> Result<Option<T>, KprError> {
+    match std::env::var(key.env_var()) {
+        Ok(value) => parse(key, &value).map(Some),
+        Err(_) => Ok(from_config),
+    }
+}
+
+fn invalid_value(key: Key, value: &str) -> KprError {
+    KprError::new(&format!("'{value}' is not a valid value for {}", key.name()))
+}
+
+fn parse_number(key: Key, value: &str) -> Result<usize, KprError> {
+    value.trim().parse().map_err(|_| invalid_value(key, value))
+}
+
+fn parse_choice<T: ValueEnum>(key: Key, value: &str) -> Result<T, KprError> {
+    T::from_str(value.trim(), true).map_err(|_| invalid_value(key, value))
+}
+
+fn parse_text(key: Key, value: &str) -> Result<String, KprError> {
+    match value.trim() {
+        "" => Err(invalid_value(key, value)),
+        value => Ok(value.to_string()),
+    }
+}
+
+fn value_name<T: ValueEnum>(value: T) -> String {
+    value.to_possible_value().expect("Every value has a name").get_name().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_round_trips_through_toml() {
+        let mut config = Config::default();
+        config.set(Key::Count, Some("25")).expect("Could not set count");
+        config.set(Key::DateFormat, Some("epoch-ms")).expect("Could not set date format");
+        config.set(Key::Language, Some("french")).expect("Could not set language");
+
+        let contents = toml::to_string(&config).expect("Could not serialize config");
+        let parsed: Config = toml::from_str(&contents).expect("Could not parse config");
+
+        assert_eq!(parsed.count, Some(25));
+        assert_eq!(parsed.date_format, Some(DateFormat::EpochMs));
+        assert_eq!(parsed.get(Key::Language), Some("french".to_string()));
+        assert_eq!(parsed.color, None);
+    }
+
+    #[test]
+    fn test_set_rejects_invalid_values() {
+        let mut config = Config::default();
+        assert!(config.set(Key::Count, Some("lots")).is_err());
+        assert!(config.set(Key::DateFormat, Some("yesterday")).is_err());
+        assert!(config.set(Key::Color, Some("sometimes")).is_err());
+    }
+
+    #[test]
+    fn test_unset_removes_value() {
+        let mut config = Config::default();
+        config.set(Key::WrapWidth, Some("100")).expect("Could not set wrap width");
+        config.set(Key::WrapWidth, None).expect("Could not unset wrap width");
+        assert_eq!(config.get(Key::WrapWidth), None);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_an_error() {
+        assert!(toml::from_str::<Config>("colour = \"never\"").is_err());
+    }
+
+    #[test]
+    fn test_config_file_wins_over_defaults() {
+        let config: Config = toml::from_str("count = 3\ndate_format = \"iso\"").expect("Could not parse config");
+        let settings = Settings::resolve(&config).expect("Could not resolve settings");
+        assert_eq!(settings.count, 3);
+        assert_eq!(settings.date_format, DateFormat::Iso);
+        assert_eq!(settings.wrap_width, 80);
+    }
+
+    #[test]
+    fn test_notebook_config_wins_over_data_directory_config() {
+        let config: Config = toml::from_str("count = 3\nlanguage = \"english\"\nnotebook = \"work\"").expect("Could not parse config");
+        let notebook: Config = toml::from_str("language = \"german\"\nnotebook = \"other\"").expect("Could not parse config");
+
+        let merged = config.with_notebook(notebook);
+        assert_eq!(merged.count, Some(3));
+        assert_eq!(merged.language, Some(Language::German));
+        assert_eq!(merged.notebook, Some("work".to_string()));
+    }
+
+    #[test]
+    fn test_sources() {
+        let config: Config = toml::from_str("count = 3\nwrap_width = 100").expect("Could not parse config");
+        let notebook: Config = toml::from_str("language = \"german\"").expect("Could not parse config");
+
+        assert_eq!(source(Key::WrapWidth, &[Key::WrapWidth], &config, Some(&notebook)), "flag");
+        assert_eq!(source(Key::Count, &[Key::WrapWidth], &config, Some(&notebook)), CONFIG_FILENAME);
+        assert_eq!(source(Key::Language, &[], &config, Some(&notebook)), format!("notebook {CONFIG_FILENAME}"));
+        assert_eq!(source(Key::SnippetWidth, &[], &config, Some(&notebook)), "default");
+    }
+
+    #[test]
+    fn test_env_var_names() {
+        assert_eq!(Key::DateFormat.env_var(), "KPR_DATE_FORMAT");
+        assert_eq!(Key::WrapWidth.name(), "wrap_width");
+    }
+}
@@ -0,0 +1,208 @@
+use chrono::{DateTime, Datelike, Duration, Local, Months, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+
+use crate::ago;
+
+/// A span of time, from `start` up to but not including `end`.
+/// An exact moment, like "3 hours ago", is a period with `start == end`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Period {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+}
+
+impl Period {
+    fn instant(at: DateTime<Local>) -> Self {
+        Period { start: at, end: at }
+    }
+
+    fn days(first: NaiveDate, count: u64) -> Option<Self> {
+        let last = first.checked_add_days(chrono::Days::new(count))?;
+        Some(Period { start: midnight(first)?, end: midnight(last)? })
+    }
+
+    /// The calendar day a moment falls on, or the period itself if it is longer.
+    pub fn whole_days(self) -> Self {
+        if self.start != self.end {
+            return self;
+        }
+        Period::days(self.start.date_naive(), 1).unwrap_or(self)
+    }
+
+    /// Whether `timestamp` is at or after the start of the period.
+    pub fn started_by(&self, timestamp: DateTime<Local>) -> bool {
+        timestamp >= self.start
+    }
+
+    /// Whether `timestamp` is before the end of the period, or at the moment if it is one.
+    pub fn not_over_by(&self, timestamp: DateTime<Local>) -> bool {
+        if self.start == self.end { timestamp <= self.end } else { timestamp < self.end }
+    }
+}
+
+/// The month starting on `first`.
+fn month(first: NaiveDate) -> Option<Period> {
+    let next = first.checked_add_months(Months::new(1))?;
+    Some(Period { start: midnight(first)?, end: midnight(next)? })
+}
+
+/// The calendar year `year`.
+fn year(year: i32) -> Option<Period> {
+    let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+    let next = NaiveDate::from_ymd_opt(year + 1, 1, 1)?;
+    Some(Period { start: midnight(first)?, end: midnight(next)? })
+}
+
+fn midnight(date: NaiveDate) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest()
+}
+
+/// Parses a time for `--since`, `--until` and `--on`, and for `date:`, `before:` and `after:` in searches.
+pub fn parse_period(text: &str) -> Result<Period, String> {
+    parse_period_at(text, Local::now()).ok_or_else(|| format!(
+        "'{text}' is not a time kpr understands, try a date like 2023-04-08, \"3 days ago\", \"yesterday\" or \"last week\""
+    ))
+}
+
+/// Understands epoch seconds or milliseconds, ISO dates and times, years and months like `2023` and `2023-04`, `today`, `yesterday`,
+/// weekdays (`tuesday`, `last tuesday`), `this`/`last` `week`/`month`/`year`,
+/// and the phrases `ago` writes, like "3 days ago".
+fn parse_period_at(text: &str, now: DateTime<Local>) -> Option<Period> {
+    let text = text.trim().to_lowercase();
+    let today = now.date_naive();
+
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+        if text.len() == 4 {
+            return year(text.parse().ok()?);
+        }
+        // Epoch seconds have had 10 digits since 2001, and anything with more than 11 digits is
+        // too far in the future to be seconds. Shorter numbers are too long ago to be notes.
+        if text.len() < 10 {
+            return None;
+        }
+        let epoch: i64 = text.parse().ok()?;
+        let timestamp = if text.len() > 11 { Local.timestamp_millis_opt(epoch) } else { Local.timestamp_opt(epoch, 0) };
+        return timestamp.single().map(Period::instant);
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(&text, "%Y-%m-%d") {
+        return Period::days(date, 1);
+    }
+    if let Ok(first) = NaiveDate::parse_from_str(&format!("{text}-01"), "%Y-%m-%d") {
+        return month(first);
+    }
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(&text.to_uppercase()) {
+        return Some(Period::instant(timestamp.with_timezone(&Local)));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dt%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dt%H:%M"] {
+        if let Ok(timestamp) = NaiveDateTime::parse_from_str(&text, format) {
+            return Local.from_local_datetime(&timestamp).earliest().map(Period::instant);
+        }
+    }
+
+    match text.as_str() {
+        "now" => return Some(Period::instant(now)),
+        "today" => return Period::days(today, 1),
+        "yesterday" => return Period::days(today.pred_opt()?, 1),
+        _ => {},
+    }
+
+    if let Some((which, unit)) = text.split_once(' ') {
+        let back = match which {
+            "this" => 0,
+            "last" => 1,
+            _ => return ago::to_datetime(&text, now).map(Period::instant),
+        };
+        return match unit.trim() {
+            "week" => {
+                let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64 + 7 * back);
+                Period::days(monday, 7)
+            },
+            "month" => month(today.with_day(1)?.checked_sub_months(Months::new(back as u32))?),
+            "year" => year(today.year() - back as i32),
+            weekday => last_weekday(today, weekday.parse().ok()?, back == 1),
+        };
+    }
+
+    if let Ok(weekday) = text.parse::<Weekday>() {
+        return last_weekday(today, weekday, false);
+    }
+
+    ago::to_datetime(&text, now).map(Period::instant)
+}
+
+/// The most recent `weekday`, which is today if it's that day, unless `before_today`.
+fn last_weekday(today: NaiveDate, weekday: Weekday, before_today: bool) -> Option<Period> {
+    let mut days_back = (7 + today.weekday().num_days_from_monday() as i64 - weekday.num_days_from_monday() as i64) % 7;
+    if days_back == 0 && before_today {
+        days_back = 7;
+    }
+    Period::days(today - Duration::days(days_back), 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Local> {
+        let timestamp = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").expect("Invalid test time");
+        Local.from_local_datetime(&timestamp).unwrap()
+    }
+
+    fn period(text: &str) -> (DateTime<Local>, DateTime<Local>) {
+        // A Wednesday.
+        let now = at("2023-04-12 15:30");
+        let period = parse_period_at(text, now).expect("Could not parse period");
+        (period.start, period.end)
+    }
+
+    #[test]
+    fn test_dates_are_whole_days() {
+        assert_eq!(period("2023-04-01"), (at("2023-04-01 00:00"), at("2023-04-02 00:00")));
+        assert_eq!(period("today"), (at("2023-04-12 00:00"), at("2023-04-13 00:00")));
+        assert_eq!(period("Yesterday"), (at("2023-04-11 00:00"), at("2023-04-12 00:00")));
+    }
+
+    #[test]
+    fn test_times_are_instants() {
+        assert_eq!(period("2023-04-01 09:15"), (at("2023-04-01 09:15"), at("2023-04-01 09:15")));
+        assert_eq!(period("2023-04-01T09:15:00"), (at("2023-04-01 09:15"), at("2023-04-01 09:15")));
+        assert_eq!(period("3 days ago"), (at("2023-04-09 15:30"), at("2023-04-09 15:30")));
+
+        let epoch = at("2023-04-01 09:15").timestamp();
+        assert_eq!(period(&epoch.to_string()).0, at("2023-04-01 09:15"));
+        assert_eq!(period(&(epoch * 1000).to_string()).0, at("2023-04-01 09:15"));
+    }
+
+    #[test]
+    fn test_calendar_periods() {
+        assert_eq!(period("this week"), (at("2023-04-10 00:00"), at("2023-04-17 00:00")));
+        assert_eq!(period("last week"), (at("2023-04-03 00:00"), at("2023-04-10 00:00")));
+        assert_eq!(period("last month"), (at("2023-03-01 00:00"), at("2023-04-01 00:00")));
+        assert_eq!(period("this year"), (at("2023-01-01 00:00"), at("2024-01-01 00:00")));
+        assert_eq!(period("2023-02"), (at("2023-02-01 00:00"), at("2023-03-01 00:00")));
+        assert_eq!(period("2022"), (at("2022-01-01 00:00"), at("2023-01-01 00:00")));
+    }
+
+    #[test]
+    fn test_weekdays() {
+        assert_eq!(period("tuesday"), (at("2023-04-11 00:00"), at("2023-04-12 00:00")));
+        assert_eq!(period("last tuesday"), (at("2023-04-11 00:00"), at("2023-04-12 00:00")));
+        assert_eq!(period("wednesday"), (at("2023-04-12 00:00"), at("2023-04-13 00:00")));
+        assert_eq!(period("last wednesday"), (at("2023-04-05 00:00"), at("2023-04-06 00:00")));
+    }
+
+    #[test]
+    fn test_on_a_moment_means_its_day() {
+        let now = at("2023-04-12 15:30");
+        let period = parse_period_at("3 days ago", now).expect("Could not parse period").whole_days();
+        assert_eq!((period.start, period.end), (at("2023-04-09 00:00"), at("2023-04-10 00:00")));
+    }
+
+    #[test]
+    fn test_nonsense_is_rejected() {
+        assert!(parse_period("the other day").is_err());
+        assert!(parse_period("2023-13-01").is_err());
+        assert!(parse_period("last fortnight").is_err());
+        assert!(parse_period("20230").is_err());
+    }
+}
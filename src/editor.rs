@@ -0,0 +1,135 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::errors::KprError;
+
+const TEMPLATE: &str = "
+# Write your message above. Lines starting with '# ' are ignored,
+# and an empty message aborts.
+";
+
+/// Opens `$VISUAL` or `$EDITOR` (falling back to `vi`) on a temporary file holding `initial`
+/// and the comment template, and returns what the user wrote with the comments stripped.
+pub fn compose(initial: &str) -> Result<String, KprError> {
+    let (filepath, mut file) = create_temp_file()?;
+    let written = write!(file, "{initial}\n{TEMPLATE}");
+    drop(file);
+
+    let edited = written
+        .map_err(KprError::from)
+        .and_then(|_| run_editor(&filepath))
+        .and_then(|_| Ok(std::fs::read_to_string(&filepath)?));
+    let _ = std::fs::remove_file(&filepath);
+
+    let message = strip_comments(&edited?);
+    if message.is_empty() {
+        return Err(KprError::new("Aborting because the message is empty."));
+    }
+    Ok(message)
+}
+
+/// Creates a new file in the temporary directory, readable only by us and with a name which
+/// can't be guessed, so that a file or link someone else left in a shared `/tmp` is never
+/// written through.
+fn create_temp_file() -> Result<(PathBuf, File), KprError> {
+    const ATTEMPTS: usize = 100;
+
+    for _ in 0..ATTEMPTS {
+        // Each `RandomState` is randomly keyed, so the hash of nothing is a random number.
+        let suffix = RandomState::new().build_hasher().finish();
+        let filepath = std::env::temp_dir().join(format!("kpr-message-{}-{suffix:016x}.txt", std::process::id()));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        match options.open(&filepath) {
+            Ok(file) => return Ok((filepath, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(KprError::new("Could not create a temporary file to write the message in."))
+}
+
+fn editor_command() -> String {
+    ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+fn run_editor(filepath: &Path) -> Result<(), KprError> {
+    let editor = editor_command();
+
+    // Editors are often configured with arguments, like `code --wait`.
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+
+    let status = Command::new(program)
+        .args(parts)
+        .arg(filepath)
+        .status()
+        .map_err(|e| KprError::new(&format!("Could not run editor '{editor}': {e}")))?;
+
+    if !status.success() {
+        return Err(KprError::new(&format!("Editor '{editor}' exited with {status}")));
+    }
+    Ok(())
+}
+
+/// Drops comment lines, which start with `# ` or are a lone `#`, so that `#tags` at the
+/// start of a line are kept.
+fn strip_comments(text: &str) -> String {
+    text
+        .lines()
+        .filter(|line| !(*line == "#" || line.starts_with("# ")))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_comments_removes_template() {
+        let text = format!("Disk full on db-1\n\nCleared old WAL files.\n{TEMPLATE}");
+        assert_eq!(strip_comments(&text), "Disk full on db-1\n\nCleared old WAL files.");
+    }
+
+    #[test]
+    fn test_strip_comments_keeps_tags_at_line_start() {
+        assert_eq!(strip_comments("#oncall paged at 3am\n#\n# a comment"), "#oncall paged at 3am");
+    }
+
+    #[test]
+    fn test_temp_files_are_new_and_private() {
+        let (first, _) = create_temp_file().expect("Could not create temp file");
+        let (second, _) = create_temp_file().expect("Could not create temp file");
+        assert_ne!(first, second);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&first).expect("Could not read metadata").permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let _ = std::fs::remove_file(first);
+        let _ = std::fs::remove_file(second);
+    }
+
+    #[test]
+    fn test_template_only_is_empty() {
+        assert_eq!(strip_comments(TEMPLATE), "");
+    }
+}
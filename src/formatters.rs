@@ -1,8 +1,12 @@
+use std::ops::Range;
+
 use chrono::{DateTime, Local};
+use colored::Colorize;
 use crate::{ago, cli::DateFormat, records::Record};
+use crate::tokens::tokenize;
 
 type Timestamp = DateTime<Local>;
-type TimestampFormatter = fn(Timestamp) -> String;
+pub type TimestampFormatter = fn(Timestamp) -> String;
 
 pub struct Formatter<MF: Fn(&String) -> String> {
     timestamp_formatter: TimestampFormatter,
@@ -22,11 +26,11 @@ impl<MF: Fn(&String) -> String> Formatter<MF> {
         (self.message_formatter)(&record.message)
     }
 
-    pub fn format_record(&self, record: &Record) -> (String, String) {
-        (self.format_timestamp(&record), self.format_message(&record))
+    pub fn format_record(&self, record: &Record) -> (String, String, String) {
+        (record.id.clone(), self.format_timestamp(record), self.format_message(record))
     }
 
-    pub fn format_records(&self, records: &[Record]) -> Vec<(String, String)> {
+    pub fn format_records(&self, records: &[Record]) -> Vec<(String, String, String)> {
         records
         .iter()
         .map(|record| self.format_record(record))
@@ -39,8 +43,108 @@ pub fn get_date_fmt_fn(format: DateFormat) -> TimestampFormatter {
     match format {
         DateFormat::Ago => ago::from_datetime,
         DateFormat::Human => |ts| ts.format("%a %e %b %y %k:%M").to_string(),
-        DateFormat::ISO => |ts| ts.format("%Y-%m-%d %H:%M:%S").to_string(),
+        DateFormat::Iso => |ts| ts.format("%Y-%m-%d %H:%M:%S").to_string(),
         DateFormat::Epoch => |ts| ts.timestamp().to_string(),
         DateFormat::EpochMs => |ts| ts.timestamp_millis().to_string(),
     }
 }
+
+
+/// Colours `matches`, byte ranges in order, in `message`. Given a `snippet_width`, a message with
+/// more words than that is cut down to the words around the best cluster of matches, with `…`
+/// where words were left out.
+pub fn highlight(message: &str, matches: &[Range<usize>], snippet_width: Option<usize>) -> String {
+    let words: Vec<Range<usize>> = tokenize(message).into_iter().map(|token| token.span).collect();
+    let (shown, text) = match snippet_width {
+        Some(width) if width > 0 && words.len() > width => {
+            let shown = best_window(&words, matches, width);
+            // Punctuation at either end of the message is kept.
+            let start = if shown.start == 0 { 0 } else { words[shown.start].start };
+            let end = if shown.end == words.len() { message.len() } else { words[shown.end - 1].end };
+            (shown, start..end)
+        },
+        _ => (0..words.len(), 0..message.len()),
+    };
+
+    let mut highlighted = String::new();
+    if shown.start > 0 {
+        highlighted.push_str("… ");
+    }
+    let mut end = text.start;
+    for found in matches {
+        let start = found.start.max(end);
+        let found_end = found.end.min(text.end);
+        if start >= found_end {
+            continue;
+        }
+        highlighted.push_str(&message[end..start]);
+        highlighted.push_str(&message[start..found_end].green().to_string());
+        end = found_end;
+    }
+    highlighted.push_str(&message[end..text.end]);
+    if shown.end < words.len() {
+        highlighted.push_str(" …");
+    }
+    highlighted
+}
+
+/// The `width` words with the most matches among them, the first such if there are several,
+/// moved so that the matches are in the middle.
+fn best_window(words: &[Range<usize>], matches: &[Range<usize>], width: usize) -> Range<usize> {
+    let matched: Vec<bool> = words
+        .iter()
+        .map(|word| matches.iter().any(|found| found.start < word.end && word.start < found.end))
+        .collect();
+
+    let mut count = matched[..width].iter().filter(|&&matched| matched).count();
+    let (mut best_start, mut best_count) = (0, count);
+    for start in 1..=words.len() - width {
+        count = count + usize::from(matched[start + width - 1]) - usize::from(matched[start - 1]);
+        if count > best_count {
+            (best_start, best_count) = (start, count);
+        }
+    }
+
+    let window = &matched[best_start..best_start + width];
+    let (Some(first), Some(last)) = (window.iter().position(|&m| m), window.iter().rposition(|&m| m)) else {
+        return 0..width;
+    };
+    let (first, last) = (best_start + first, best_start + last);
+    let centred = (first + last + 1).saturating_sub(width) / 2;
+    let start = centred.min(first).max((last + 1).saturating_sub(width)).min(words.len() - width);
+    start..start + width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches_of(message: &str, words: &[&str]) -> Vec<Range<usize>> {
+        tokenize(message).into_iter().filter(|token| words.contains(&token.text)).map(|token| token.span).collect()
+    }
+
+    #[test]
+    fn test_short_messages_are_shown_whole() {
+        let message = "the disk is full";
+        let matches = matches_of(message, &["disk"]);
+        assert_eq!(highlight(message, &matches, Some(10)), format!("the {} is full", "disk".green()));
+        assert_eq!(highlight(message, &[], Some(2)), "the disk …");
+    }
+
+    #[test]
+    fn test_snippet_is_centred_on_the_best_cluster() {
+        let message = "one disk two three four five six seven eight nine disk full ten eleven twelve";
+        let matches = matches_of(message, &["disk", "full"]);
+
+        let snippet = highlight(message, &matches, Some(5));
+        assert_eq!(snippet, format!("… eight nine {} {} ten …", "disk".green(), "full".green()));
+        assert_eq!(highlight(message, &matches, None).matches("disk").count(), 2);
+    }
+
+    #[test]
+    fn test_snippet_at_the_end_has_no_trailing_elision() {
+        let message = "a b c d e f g h disk full";
+        let matches = matches_of(message, &["disk", "full"]);
+        assert_eq!(highlight(message, &matches, Some(4)), format!("… g h {} {}", "disk".green(), "full".green()));
+    }
+}
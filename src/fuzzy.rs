@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+/// The Levenshtein distance between `a` and `b`, or `None` if it is more than `max`.
+/// Gives up early once every alignment is already further apart than `max`.
+pub fn levenshtein(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, a_char) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().all(|&distance| distance > max) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    Some(previous[b.len()]).filter(|&distance| distance <= max)
+}
+
+/// A BK-tree, which finds the words within some edit distance of a word without comparing it
+/// against all of them. Each child is at a different distance from its parent, and by the
+/// triangle inequality only children within `max` of the word's distance can hold matches.
+#[derive(Default)]
+pub struct BkTree {
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    word: String,
+    children: HashMap<usize, usize>,
+}
+
+impl BkTree {
+    pub fn new<'a>(words: impl IntoIterator<Item=&'a str>) -> Self {
+        let mut tree = BkTree::default();
+        for word in words {
+            tree.insert(word);
+        }
+        tree
+    }
+
+    pub fn insert(&mut self, word: &str) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node { word: word.to_string(), children: HashMap::new() });
+            return;
+        }
+
+        let mut i = 0;
+        loop {
+            let distance = levenshtein(&self.nodes[i].word, word, usize::MAX).expect("Distance is unbounded");
+            if distance == 0 {
+                return;
+            }
+            match self.nodes[i].children.get(&distance) {
+                Some(&child) => i = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node { word: word.to_string(), children: HashMap::new() });
+                    self.nodes[i].children.insert(distance, child);
+                    return;
+                },
+            }
+        }
+    }
+
+    /// The words within `max` edits of `word`, closest first.
+    pub fn find(&self, word: &str, max: usize) -> Vec<(usize, &str)> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+
+        let mut to_visit = vec![0];
+        while let Some(i) = to_visit.pop() {
+            let node = &self.nodes[i];
+            let distance = levenshtein(&node.word, word, usize::MAX).expect("Distance is unbounded");
+            if distance <= max {
+                found.push((distance, node.word.as_str()));
+            }
+            to_visit.extend(
+                node.children
+                    .iter()
+                    .filter(|(&child_distance, _)| child_distance.abs_diff(distance) <= max)
+                    .map(|(_, &child)| child)
+            );
+        }
+
+        found.sort_unstable();
+        found
+    }
+}
+
+/// How many typos to allow in a word: none in very short words, which would match too much.
+pub fn max_typos(word: &str) -> usize {
+    match word.chars().count() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("kubernetes", "kubernetes", 2), Some(0));
+        assert_eq!(levenshtein("kubernets", "kubernetes", 2), Some(1));
+        assert_eq!(levenshtein("dsik", "disk", 2), Some(2));
+        assert_eq!(levenshtein("kitten", "sitting", 3), Some(3));
+        assert_eq!(levenshtein("kitten", "sitting", 2), None);
+        assert_eq!(levenshtein("a", "abcd", 2), None);
+        assert_eq!(levenshtein("", "ab", 2), Some(2));
+    }
+
+    #[test]
+    fn test_bk_tree_finds_close_words() {
+        let tree = BkTree::new(["deploy", "deplo", "display", "rollback", "kubernet", "api"]);
+
+        assert_eq!(tree.find("deploi", 1), vec![(1, "deplo"), (1, "deploy")]);
+        assert_eq!(tree.find("kubernt", 2), vec![(1, "kubernet")]);
+        assert!(tree.find("zebra", 2).is_empty());
+    }
+
+    #[test]
+    fn test_bk_tree_agrees_with_brute_force() {
+        let words = ["disk", "desk", "dusk", "risk", "diskette", "dish", "fish", "full", "fill", "fall"];
+        let tree = BkTree::new(words);
+
+        for query in ["disk", "fil", "dsk", "fullest"] {
+            for max in 0..=3 {
+                let mut expected: Vec<(usize, &str)> = words
+                    .iter()
+                    .filter_map(|word| Some((levenshtein(word, query, max)?, *word)))
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(tree.find(query, max), expected);
+            }
+        }
+    }
+}
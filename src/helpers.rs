@@ -1,17 +1,19 @@
+use std::io::{IsTerminal, Read};
+
 use chrono::{Local, DateTime};
 
 use crate::{tables::make_table, records::Record};
 
 
-pub fn format_records(records: &[Record], formatter: fn(DateTime<Local>) -> String) -> Vec<(String, String)> {
+pub fn format_records(records: &[Record], formatter: fn(DateTime<Local>) -> String) -> Vec<(String, String, String)> {
     records
     .iter()
-    .map(|record| (formatter(record.timestamp), record.message.clone()))
+    .map(|record| (record.id.clone(), formatter(record.timestamp), record.message.clone()))
     .collect()
 }
 
 pub fn format_records_to_table(records: &[Record], formatter: fn(DateTime<Local>) -> String) -> Vec<String> {
-    let rows: Vec<(String, String)> = format_records(records, formatter);
+    let rows: Vec<(String, String, String)> = format_records(records, formatter);
     make_table(&rows)       
 }
 
@@ -19,10 +21,46 @@ pub fn format_records_to_table(records: &[Record], formatter: fn(DateTime<Local>
 pub fn words_from_stdin() -> Result<Vec<String>, std::io::Error> {
     let mut buffer = String::new();
     std::io::stdin().read_line(&mut buffer)?;
-    let message = buffer.trim();
-    let message = message
+    let message = buffer
         .split_whitespace()
         .map(ToString::to_string)
         .collect();
     Ok(message)
 }
+
+/// Reads the whole of stdin as a message, so that `cmd | kpr keep` keeps every line.
+pub fn message_from_stdin() -> Result<String, std::io::Error> {
+    let mut buffer = String::new();
+    std::io::stdin().read_to_string(&mut buffer)?;
+    Ok(without_final_newlines(&buffer).to_string())
+}
+
+/// Drops the newlines piped output ends with, but keeps any indentation, as in a stack trace.
+fn without_final_newlines(text: &str) -> &str {
+    text.trim_end_matches(['\n', '\r'])
+}
+
+pub fn stdin_is_terminal() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// The 64 bit FNV-1a hash of `bytes`: fast, and stable across kpr versions and platforms.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    bytes
+        .iter()
+        .fold(FNV_OFFSET, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piped_messages_keep_their_indentation() {
+        let trace = "    at main.rs:10\n    at lib.rs:3\r\n\n";
+        assert_eq!(without_final_newlines(trace), "    at main.rs:10\n    at lib.rs:3");
+    }
+}
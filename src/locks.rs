@@ -15,7 +15,7 @@ impl<'a> LockGuard<'a> {
 
 impl<'a> Drop for LockGuard<'a> {
     fn drop(&mut self) {
-        let _ = self.file.unlock();
+        let _ = FileExt::unlock(self.file);
     }
 }
 
@@ -25,10 +25,7 @@ mod tests {
     use crate::store::{full_path, open_or_create};
 
     use super::*;
-    use std::fs::File;
-    use std::io::Write;
     use std::thread;
-    use std::time::Duration;
 
     #[test]
     fn test_lock_guard_locks_file() {
@@ -43,10 +40,8 @@ mod tests {
         let filepath = filepath.clone();
         let handle = thread::spawn(move || {
             let file = open_or_create(&filepath, false).expect("Could not open store file");
-            match file.try_lock_exclusive() {
-                Ok(_) => false, // Locking the file succeeded, which is not expected
-                Err(_) => true, // Locking the file failed, which is expected
-            }
+            // Locking the file should fail while the guard is held.
+            file.try_lock_exclusive().is_err()
         });
     
         // The other thread should not be able to lock the file.
@@ -72,8 +67,7 @@ mod tests {
         // Try to open the file again in a separate thread.
         let filepath = filepath.clone();
         let handle = thread::spawn(move || {
-            let file = open_or_create(&filepath, false).expect("Could not open store file");
-            file
+            open_or_create(&filepath, false).expect("Could not open store file")
         });
     
         // The other thread should be able to open the file now.
@@ -2,47 +2,111 @@ mod errors;
 use errors::KprError;
 
 mod cli;
-use cli::{get_cmd, Commands, ListArgs, SearchArgs};
+mod config;
+mod dates;
+use config::{Config, Key, Settings};
+use clap::ValueEnum;
+use colored::Colorize;
+use cli::{get_cli, Cli, Commands, ConfigCommands, DateFormat, ListArgs, SearchArgs};
 
 mod helpers;
-use helpers::{words_from_stdin, format_records_to_table};
+use helpers::{words_from_stdin, message_from_stdin, stdin_is_terminal, format_records_to_table};
 use formatters::{get_date_fmt_fn, Formatter};
 
+mod editor;
 mod locks;
+mod notebooks;
+mod query;
+mod scan;
 mod search;
 mod store;
 mod ago;
+mod browse;
 mod tables;
+mod tokens;
 mod records;
+mod saved;
 mod formatters;
-use store::STORE_FILENAME;
+mod fuzzy;
 use tables::make_table;
-use records::Record;
+use query::Query;
+use records::{Entry, Record};
+use saved::SavedSearches;
+use chrono::Local;
+use std::path::{Path, PathBuf};
 
 
-fn keep(message_parts: Vec<String>) -> Result<(), KprError> {
+/// Works out the message to store: from the command line, from piped input, or from an editor.
+fn compose_message(message_parts: Vec<String>, use_editor: bool, initial: &str) -> Result<String, KprError> {
+    if use_editor || (message_parts.is_empty() && stdin_is_terminal()) {
+        let initial = if message_parts.is_empty() { initial.to_string() } else { message_parts.join(" ") };
+        return editor::compose(&initial);
+    }
 
-    let message_parts = if message_parts.is_empty() {
-        println!("Your message: ");
-        words_from_stdin()?
+    let message = if message_parts.is_empty() {
+        message_from_stdin()?
     } else {
-        message_parts
+        message_parts.join(" ")
     };
 
-    let message = message_parts.join(" ");
+    if message.trim().is_empty() {
+        return Err(KprError::new("Aborting because the message is empty."));
+    }
+    Ok(message)
+}
+
+fn keep(message_parts: Vec<String>, use_editor: bool) -> Result<(), KprError> {
+    let message = compose_message(message_parts, use_editor, "")?;
+
     let record = Record::create(message);
-    let line_number = store::write(&record)?;
+    // Loading the index notices the new line and indexes it.
+    store::write(&Entry::Note(record), |_| search::Index::load().map(drop))?;
+    Ok(())
+}
+
+fn edit(note: &str, message_parts: Vec<String>, use_editor: bool) -> Result<(), KprError> {
+    let (_, original) = store::find(note)?;
+
+    let message = compose_message(message_parts, use_editor, &original.message)?;
 
-    let mut index = search::Index::load();
-    index.add_line(line_number, &record);
-    index.save();
+    store::change(&original.id, |current| {
+        // The editor may have been open for a while.
+        if current.message != original.message {
+            return Err(KprError::new(&format!("The message was changed while you were editing it, so your edit was not kept:\n{message}")));
+        }
+        Ok(Entry::Edit(Record::new(current.id, Local::now(), message)))
+    }, |_| search::Index::load().map(drop))?;
     Ok(())
 }
 
+fn remove(note: &str) -> Result<(), KprError> {
+    store::change(note, |current| {
+        Ok(Entry::Delete { id: current.id, timestamp: Local::now() })
+    }, |_| search::Index::load().map(drop))?;
+    Ok(())
+}
 
-fn list(args: &ListArgs) {
-    let records = store::load_records(Some(args.n));
-    let fmt_fn = get_date_fmt_fn(args.date_format);
+fn compact() -> Result<usize, KprError> {
+    let removed = store::compact(|| search::Index::rebuild()?.save())?;
+    Ok(removed)
+}
+
+
+fn list(args: &ListArgs) -> Result<(), KprError> {
+    let settings = config::settings();
+    let n = args.n.unwrap_or(settings.count);
+    let matching = match Query::parse(&args.query.join(" "))? {
+        Some(query) => search::matching_ids(&query)?,
+        None => None,
+    };
+    // Reading can stop at `--since` when the notes are known to be in the order they were kept.
+    let since = args.dates.earliest().filter(|_| search::store_is_in_date_order());
+    let records = store::load_records(Some(n), since, |record| {
+        record.has_tags(&args.tags)
+            && args.dates.contains(record.timestamp)
+            && matching.as_ref().is_none_or(|ids| ids.contains(&record.id))
+    });
+    let fmt_fn = get_date_fmt_fn(args.date_format.unwrap_or(settings.date_format));
     let formatted_records = format_records_to_table(&records, fmt_fn);
 
     // let lines = store::load_lines(Some(args.n));
@@ -53,49 +117,247 @@ fn list(args: &ListArgs) {
     for record in formatted_records {
         println!("{record}");
     }
+    Ok(())
 }
 
 fn search(args: SearchArgs) -> Result<(), KprError> {
-    let query = if args.query.is_empty() {
+    let save = args.save.clone();
+    let query = show_search(args)?;
+
+    // Only keep searches which ran, so a bad query or pattern isn't saved. The query is the one
+    // that ran, so a query typed at the prompt is kept too.
+    if let Some(name) = save {
+        let notebook = notebooks::current();
+        let mut searches = SavedSearches::load(&notebook.root)?;
+        searches.insert(&name, cli::search_to_save(std::env::args_os().collect(), query, &notebook.name))?;
+        searches.save(&notebook.root)?;
+    }
+    Ok(())
+}
+
+/// Runs a search and shows what it found, returning the words of the query it ran.
+fn show_search(args: SearchArgs) -> Result<Vec<String>, KprError> {
+    let query = if args.query.is_empty() && args.tags.is_empty() && args.dates.is_empty() {
         println!("Search for: ");
         words_from_stdin()?
     } else {
-        args.query
+        args.query.clone()
     };
 
-    let records = search::search(&query, args.n);
-    let format_date = get_date_fmt_fn(args.date_format);
-    
-    let highlight_matches = search::make_match_formatter(&query);
+    let settings = config::settings();
+    let format_date = get_date_fmt_fn(args.date_format.unwrap_or(settings.date_format));
+    let snippet_width = if args.full { None } else { Some(settings.snippet_width) };
+
+    if args.regex || args.literal {
+        let pattern = scan::pattern(&query.join(" "), args.literal, args.ignore_case)?;
+        let records = scan::scan(&pattern, &args);
+        let formatter = Formatter::new(format_date, scan::make_span_formatter(pattern, snippet_width));
+        print_table(&formatter.format_records(&records));
+        return Ok(query);
+    }
+
+    let parsed = Query::parse(&query.join(" "))?;
+    let found = search::search(parsed.as_ref(), &args)?;
+    if found.records.is_empty() && !found.suggestions.is_empty() {
+        println!("did you mean: {}?", found.suggestions.join(", "));
+        return Ok(query);
+    }
+
+    let highlight_matches = search::make_match_formatter(found.stems, snippet_width);
     let formatter = Formatter::new(format_date, highlight_matches);
-    let formatted_results = formatter.format_records(&records);
+    print_table(&formatter.format_records(&found.records));
+    Ok(query)
+}
+
+fn related(note: &str, n: Option<usize>, date_format: Option<DateFormat>, full: bool) -> Result<(), KprError> {
+    let settings = config::settings();
+    let found = search::related(note, n.unwrap_or(settings.count))?;
+
+    let format_date = get_date_fmt_fn(date_format.unwrap_or(settings.date_format));
+    let snippet_width = if full { None } else { Some(settings.snippet_width) };
+    let formatter = Formatter::new(format_date, search::make_match_formatter(found.stems, snippet_width));
+    print_table(&formatter.format_records(&found.records));
+    Ok(())
+}
+
+fn list_saved() -> Result<(), KprError> {
+    let searches = SavedSearches::load(&notebooks::current().root)?;
+    let width = searches.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+    for (name, search) in searches.iter() {
+        // Shown as it would be typed, so searches of other notebooks start with their `-b`.
+        let notebook = Some(&search.notebook)
+            .filter(|notebook| notebook.as_str() != notebooks::DEFAULT_NOTEBOOK)
+            .into_iter()
+            .flat_map(|notebook| ["-b".to_string(), notebook.clone()]);
+        let quoted = |words: Vec<String>| -> String {
+            let words: Vec<String> = words
+                .into_iter()
+                .map(|word| if word.contains(' ') { format!("\"{word}\"") } else { word })
+                .collect();
+            words.join(" ")
+        };
+        let options = quoted(notebook.chain(search.options.iter().cloned()).collect());
+        println!("{name:<width$}  {}  {}", quoted(search.query.clone()), options.bright_black());
+    }
+    Ok(())
+}
 
-    let table = make_table(&formatted_results);
-    for line in table {
+/// Runs a saved search. Its notebook was selected in `setup`.
+fn run_saved(name: &str) -> Result<(), KprError> {
+    let searches = SavedSearches::load(&notebooks::current().root)?;
+    search(cli::search_args_of(searches.get(name)?)?)
+}
+
+fn print_table(formatted_records: &[(String, String, String)]) {
+    for line in make_table(formatted_records) {
         println!("{line}");
     }
+}
+
+fn tags() -> Result<(), KprError> {
+    let counts = search::Index::load()?.tag_counts();
+    let width = counts.iter().map(|(_, count)| count.to_string().len()).max().unwrap_or(0);
+    for (tag, count) in counts {
+        println!("{count:>width$}  #{tag}");
+    }
     Ok(())
 }
 
-fn reindex() {
-    let index = search::Index::from_store_path(STORE_FILENAME);
-    index.save();
+fn browse() -> Result<(), KprError> {
+    let format_date = get_date_fmt_fn(config::settings().date_format);
+    let mut browser = browse::Browser::load(format_date)?;
+    loop {
+        match browse::run(&mut browser)? {
+            browse::Exit::Quit => return Ok(()),
+            browse::Exit::Print(record) => {
+                println!("{}", record.message);
+                return Ok(());
+            },
+            browse::Exit::Edit(record) => {
+                edit(&record.id, Vec::new(), true)?;
+                browser.reload()?;
+            },
+        }
+    }
+}
+
+fn list_notebooks() -> Result<(), KprError> {
+    let current = notebooks::current();
+    for name in notebooks::list(&current.root)? {
+        let marker = if name == current.name { "*" } else { " " };
+        println!("{marker} {name}");
+    }
+    Ok(())
 }
 
-fn dispatch(cmd: Commands) -> Result<(), KprError> {
+/// `flagged` are the settings given as flags, which `get` and `list` show in effect.
+fn configure(cmd: ConfigCommands, flagged: &[Key]) -> Result<(), KprError> {
+    let notebook = notebooks::current();
+    let config = Config::load(&notebook.root)?;
+    // The default notebook's own config is the data directory's.
+    let local = match notebook.name.as_str() {
+        notebooks::DEFAULT_NOTEBOOK => None,
+        _ => Some(Config::load(&notebook.dir())?),
+    };
+    // These are worked out from the same config files in `setup`, with the flags on top.
+    let settings = config::settings();
+
     match cmd {
-        Commands::Keep { message } => {
-            keep(message)?;
+        ConfigCommands::Get { key } => {
+            println!("{}", settings.get(key));
+        },
+        ConfigCommands::Set { key, value, local } => {
+            change_setting(key, Some(&value), local)?;
+        },
+        ConfigCommands::Unset { key, local } => {
+            change_setting(key, None, local)?;
+        },
+        ConfigCommands::List => {
+            for key in Key::value_variants() {
+                let source = config::source(*key, flagged, &config, local.as_ref());
+                println!("{} = {}  {}", key.name(), settings.get(*key), format!("({source})").bright_black());
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Sets or removes a setting in the data directory's config, or in the notebook's own if `local`.
+fn change_setting(key: Key, value: Option<&str>, local: bool) -> Result<(), KprError> {
+    if local && key == Key::Notebook {
+        return Err(KprError::new("The notebook to use can only be set for the whole data directory."));
+    }
+
+    let notebook = notebooks::current();
+    let dir = if local { notebook.dir() } else { notebook.root.clone() };
+    let mut config = Config::load(&dir)?;
+    config.set(key, value)?;
+    config.save(&dir)
+}
+
+fn migrate() -> Result<Option<PathBuf>, KprError> {
+    let backup = store::migrate(|| search::Index::rebuild()?.save())?;
+    Ok(backup)
+}
+
+fn reindex() -> Result<(), KprError> {
+    store::with_lock(|_, _| search::Index::rebuild()?.save())?;
+    Ok(())
+}
+
+fn dispatch(cmd: Commands, flagged: &[Key]) -> Result<(), KprError> {
+    match cmd {
+        Commands::Keep { message, editor } => {
+            keep(message, editor)?;
             println!("kpr kept your message.");
         },
         Commands::List(args) => {
-            list(&args);
+            list(&args)?;
         },
         Commands::Search(args) => {
             search(args)?;
         },
+        Commands::Saved => {
+            list_saved()?;
+        },
+        Commands::Run { name } => {
+            run_saved(&name)?;
+        },
+        Commands::Related { note, n, date_format, full } => {
+            related(&note, n, date_format, full)?;
+        },
+        Commands::Edit { note, message, editor } => {
+            edit(&note, message, editor)?;
+            println!("kpr edited your message.");
+        },
+        Commands::Rm { note } => {
+            remove(&note)?;
+            println!("kpr deleted your message.");
+        },
+        Commands::Compact => {
+            let removed = compact()?;
+            println!("kpr compacted your messages, removing {removed} old entries.");
+        },
+        Commands::Migrate => {
+            match migrate()? {
+                Some(backup) => println!("kpr upgraded your messages to format {}. The old store is backed up at {}.", store::FORMAT_VERSION, backup.display()),
+                None => println!("kpr found your messages are already in format {}.", store::FORMAT_VERSION),
+            }
+        },
+        Commands::Tags => {
+            tags()?;
+        },
+        Commands::Notebooks => {
+            list_notebooks()?;
+        },
+        Commands::Browse => {
+            browse()?;
+        },
+        Commands::Config(cmd) => {
+            configure(cmd, flagged)?;
+        },
         Commands::Index => {
-            reindex();
+            reindex()?;
             println!("kpr indexed your messages from scratch.");
         },
     };
@@ -103,8 +365,73 @@ fn dispatch(cmd: Commands) -> Result<(), KprError> {
 }
 
 
+/// Works out the notebook and the settings (flag > environment variable > notebook config file >
+/// config file > default) to use.
+fn setup(cli: &Cli) -> Result<(), KprError> {
+    let root = notebooks::resolve_root(cli.store.clone())?;
+    let config = Config::load(&root)?;
+
+    let notebook = match cli.notebook.clone().or(saved_search_notebook(&cli.command, &root)?) {
+        Some(notebook) => notebook,
+        None => Settings::resolve(&config)?.notebook,
+    };
+    notebooks::select(root, &notebook, writes_notebook(&cli.command))?;
+
+    // A notebook's own config.toml wins over the data directory's.
+    let config = match notebook.as_str() {
+        notebooks::DEFAULT_NOTEBOOK => config,
+        _ => config.with_notebook(Config::load(&notebooks::current().dir())?),
+    };
+
+    let mut settings = Settings::resolve(&config)?;
+    settings.notebook = notebook;
+    if let Some(color) = cli.color { settings.color = color }
+    if let Some(width) = cli.width { settings.wrap_width = width }
+
+    settings.apply();
+    Ok(())
+}
+
+/// Whether `command` writes to the notebook, and so can start a new one.
+fn writes_notebook(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Keep { .. } | Commands::Edit { .. } | Commands::Rm { .. } | Commands::Compact | Commands::Migrate
+            | Commands::Config(ConfigCommands::Set { local: true, .. } | ConfigCommands::Unset { local: true, .. })
+    )
+}
+
+/// The notebook a saved search was saved in, if `command` runs one.
+fn saved_search_notebook(command: &Commands, root: &Path) -> Result<Option<String>, KprError> {
+    match command {
+        Commands::Run { name } => Ok(Some(SavedSearches::load(root)?.get(name)?.notebook.clone())),
+        _ => Ok(None),
+    }
+}
+
+/// The settings given as flags, which win over every other source.
+fn flagged_settings(cli: &Cli) -> Vec<Key> {
+    [
+        (Key::Notebook, cli.notebook.is_some()),
+        (Key::Color, cli.color.is_some()),
+        (Key::WrapWidth, cli.width.is_some()),
+    ]
+        .into_iter()
+        .filter_map(|(key, flagged)| flagged.then_some(key))
+        .collect()
+}
+
+fn run(cli: Cli) -> Result<(), KprError> {
+    setup(&cli)?;
+    let flagged = flagged_settings(&cli);
+    dispatch(cli.command, &flagged)
+}
+
 fn main() {
-    let cmd = get_cmd();
-    dispatch(cmd).expect("everything is broken");
+    let cli = get_cli();
+    if let Err(error) = run(cli) {
+        eprintln!("kpr: {error}");
+        std::process::exit(1);
+    }
 
 }
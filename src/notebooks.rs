@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::errors::KprError;
+
+/// The notebook used when none is chosen. Its files live directly in the data directory.
+pub const DEFAULT_NOTEBOOK: &str = "default";
+const NOTEBOOKS_DIRNAME: &str = "notebooks";
+
+static CURRENT: OnceLock<Notebook> = OnceLock::new();
+
+/// A notebook is a directory holding its own store, index and stopwords.
+pub struct Notebook {
+    /// The kpr data directory, `~/.kpr` unless `--store` or `KPR_HOME` say otherwise.
+    pub root: PathBuf,
+    pub name: String,
+}
+
+impl Notebook {
+    pub fn new(root: PathBuf, name: &str) -> Result<Self, KprError> {
+        check_name(name)?;
+        Ok(Notebook { root, name: name.to_string() })
+    }
+
+    /// The notebook called `name`, making its directory if `create`, for commands which write to
+    /// it. Otherwise a notebook that doesn't exist is an error, so that a misspelled `-b` doesn't
+    /// quietly start a new notebook. The default notebook is the data directory, which is always made.
+    pub fn open(root: PathBuf, name: &str, create: bool) -> Result<Self, KprError> {
+        let notebook = Notebook::new(root, name)?;
+        if create || name == DEFAULT_NOTEBOOK {
+            std::fs::create_dir_all(notebook.dir())?;
+        } else if !notebook.dir().is_dir() {
+            return Err(KprError::new(&format!("There is no notebook named '{name}'. Keep a message in it to start it.")));
+        }
+        Ok(notebook)
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        if self.name == DEFAULT_NOTEBOOK {
+            return self.root.clone();
+        }
+        self.root.join(NOTEBOOKS_DIRNAME).join(&self.name)
+    }
+}
+
+pub fn default_root() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".kpr"))
+}
+
+/// The data directory: `root` if one was given, otherwise `~/.kpr`.
+pub fn resolve_root(root: Option<PathBuf>) -> Result<PathBuf, KprError> {
+    match root.or_else(default_root) {
+        Some(root) => Ok(root),
+        None => Err(KprError::new("Could not find your home directory, please pass --store or set KPR_HOME")),
+    }
+}
+
+/// Chooses the notebook that the rest of kpr reads and writes.
+pub fn select(root: PathBuf, name: &str, create: bool) -> Result<(), KprError> {
+    let notebook = Notebook::open(root, name, create)?;
+
+    CURRENT
+        .set(notebook)
+        .map_err(|_| KprError::new("A notebook has already been selected"))
+}
+
+/// The selected notebook, or the default one in `~/.kpr` if none has been selected.
+pub fn current() -> &'static Notebook {
+    CURRENT.get_or_init(|| {
+        let root = default_root().expect("Could not find home directory");
+        Notebook { root, name: DEFAULT_NOTEBOOK.to_string() }
+    })
+}
+
+/// The names of all the notebooks in the data directory, the default one first.
+pub fn list(root: &Path) -> Result<Vec<String>, KprError> {
+    let mut names = Vec::new();
+
+    let notebooks_dir = root.join(NOTEBOOKS_DIRNAME);
+    if notebooks_dir.is_dir() {
+        for entry in std::fs::read_dir(notebooks_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() { continue }
+            names.extend(entry.file_name().to_str().map(ToString::to_string));
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT_NOTEBOOK.to_string());
+
+    Ok(names)
+}
+
+fn check_name(name: &str) -> Result<(), KprError> {
+    let is_valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if !is_valid {
+        return Err(KprError::new(&format!(
+            "'{name}' is not a valid notebook name, use letters, numbers, '-', '_' and '.'"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_notebook_is_the_root() {
+        let notebook = Notebook::new(PathBuf::from("/data"), DEFAULT_NOTEBOOK).expect("Invalid notebook");
+        assert_eq!(notebook.dir(), PathBuf::from("/data"));
+    }
+
+    #[test]
+    fn test_named_notebook_has_its_own_dir() {
+        let notebook = Notebook::new(PathBuf::from("/data"), "work").expect("Invalid notebook");
+        assert_eq!(notebook.dir(), PathBuf::from("/data/notebooks/work"));
+    }
+
+    #[test]
+    fn test_notebook_names_cannot_escape_the_root() {
+        assert!(Notebook::new(PathBuf::from("/data"), "../work").is_err());
+        assert!(Notebook::new(PathBuf::from("/data"), "a/b").is_err());
+        assert!(Notebook::new(PathBuf::from("/data"), "..").is_err());
+        assert!(Notebook::new(PathBuf::from("/data"), "").is_err());
+    }
+
+    #[test]
+    fn test_only_writes_start_notebooks() {
+        let root = std::env::temp_dir().join(format!("kpr-test-open-notebooks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(Notebook::open(root.clone(), "typo", false).is_err());
+        assert!(!root.join(NOTEBOOKS_DIRNAME).join("typo").exists());
+        assert!(Notebook::open(root.clone(), DEFAULT_NOTEBOOK, false).is_ok());
+
+        Notebook::open(root.clone(), "work", true).expect("Could not start notebook");
+        assert!(Notebook::open(root.clone(), "work", false).is_ok());
+
+        std::fs::remove_dir_all(&root).expect("Could not clean up");
+    }
+
+    #[test]
+    fn test_list_finds_notebook_dirs() {
+        let root = std::env::temp_dir().join(format!("kpr-test-notebooks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join(NOTEBOOKS_DIRNAME).join("work")).expect("Could not create dir");
+        std::fs::create_dir_all(root.join(NOTEBOOKS_DIRNAME).join("personal")).expect("Could not create dir");
+
+        let names = list(&root).expect("Could not list notebooks");
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(names, vec!["default", "personal", "work"]);
+    }
+}
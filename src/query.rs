@@ -0,0 +1,463 @@
+use std::ops::RangeInclusive;
+
+use crate::dates::{parse_period, Period};
+use crate::errors::KprError;
+use crate::records::{normalize_tag, Record};
+
+/// How far apart the two sides of a plain `NEAR` may be.
+pub const DEFAULT_NEAR_DISTANCE: u32 = 5;
+
+/// Stands for any letters in a search word, as in `INC-20*`.
+pub const WILDCARD: char = '*';
+
+/// A parsed search, like `deploy -staging`, `(api | web) AND "disk full"` or `disk NEAR/3 full`.
+/// Words next to each other must all match, as if joined with `AND`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Query {
+    Term(String),
+    /// Quoted words, which must appear together and in order. Words which look like
+    /// operators are matched as they are.
+    Phrase(Vec<String>),
+    /// Two words or phrases, at most this many words apart.
+    Near(Vec<String>, Vec<String>, u32),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Field(Field),
+}
+
+/// A `field:value` term, which matches notes by when they were kept, their tags, ID or length
+/// rather than by their words, like `tag:oncall`, `after:yesterday` or `len:>200`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Field {
+    Tag(String),
+    /// Kept during the period, or on the day of a moment.
+    Date(Period),
+    /// Kept before the period started.
+    Before(Period),
+    /// Kept after the period ended.
+    After(Period),
+    /// The ID starts with this.
+    Id(String),
+    /// The message is this many characters long.
+    Length(RangeInclusive<usize>),
+}
+
+impl Field {
+    /// Reads a word like `tag:oncall`, or returns `None` if it doesn't start with a field's name.
+    fn parse(word: &str) -> Result<Option<Field>, KprError> {
+        let Some((name, value)) = word.split_once(':') else { return Ok(None) };
+        let period = || parse_period(value).map_err(|e| parse_error(&e));
+
+        let field = match name {
+            "tag" => match normalize_tag(value) {
+                tag if tag.is_empty() => return Err(parse_error(&format!("'{value}' is not a tag"))),
+                tag => Field::Tag(tag),
+            },
+            "date" => Field::Date(period()?.whole_days()),
+            "before" => Field::Before(period()?),
+            "after" => Field::After(period()?),
+            "id" if !value.is_empty() => Field::Id(value.to_lowercase()),
+            "id" => return Err(parse_error("id: needs the start of an ID")),
+            "len" => Field::Length(parse_length(value)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(field))
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        let timestamp = record.timestamp;
+        match self {
+            Field::Tag(tag) => record.tags.contains(tag),
+            Field::Date(period) => period.started_by(timestamp) && period.not_over_by(timestamp),
+            Field::Before(period) => !period.started_by(timestamp),
+            Field::After(period) => !period.not_over_by(timestamp),
+            Field::Id(start) => record.id.starts_with(start.as_str()),
+            Field::Length(lengths) => lengths.contains(&record.message.chars().count()),
+        }
+    }
+}
+
+/// Reads the lengths `len:` allows: `200`, `>200`, `>=200`, `<200` or `<=200`.
+fn parse_length(value: &str) -> Result<RangeInclusive<usize>, KprError> {
+    let (comparison, number) = match value.find(|c: char| c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let length: usize = number
+        .parse()
+        .map_err(|_| parse_error(&format!("len:{value} needs a number of characters, like len:>200")))?;
+
+    // Nothing is shorter than `len:<0`, so it allows no lengths at all.
+    let none = RangeInclusive::new(1, 0);
+    match comparison {
+        "" | "=" => Ok(length..=length),
+        ">" => Ok(length.checked_add(1).map_or(none, |least| least..=usize::MAX)),
+        ">=" => Ok(length..=usize::MAX),
+        "<" => Ok(length.checked_sub(1).map_or(none, |most| 0..=most)),
+        "<=" => Ok(0..=length),
+        _ => Err(parse_error(&format!("len:{value} should compare with >, >=, < or <="))),
+    }
+}
+
+impl Query {
+    /// Parses a search, or returns `None` if there is nothing to search for.
+    pub fn parse(text: &str) -> Result<Option<Query>, KprError> {
+        let mut parser = Parser { tokens: tokenize(text), position: 0 };
+        if parser.peek().is_none() {
+            return Ok(None);
+        }
+
+        let query = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(Some(query)),
+            Some(token) => Err(parse_error(&format!("unexpected '{token}'"))),
+        }
+    }
+
+    /// Splits each word into the words `split` finds in it. A word which splits into several
+    /// becomes a phrase, so `db-1` finds notes with `db` just before `1`. Words which split into
+    /// none are kept as they are.
+    pub fn split_words(&self, split: &impl Fn(&str) -> Vec<String>) -> Query {
+        let split_all = |words: &[String]| -> Vec<String> { words.iter().flat_map(|word| split(word)).collect() };
+        match self {
+            Query::Term(word) => match split(word).as_slice() {
+                [] => Query::Term(word.clone()),
+                [part] => Query::Term(part.clone()),
+                parts => Query::Phrase(parts.to_vec()),
+            },
+            Query::Phrase(words) => Query::Phrase(split_all(words)),
+            Query::Near(left, right, distance) => Query::Near(split_all(left), split_all(right), *distance),
+            Query::And(queries) => Query::And(queries.iter().map(|query| query.split_words(split)).collect()),
+            Query::Or(queries) => Query::Or(queries.iter().map(|query| query.split_words(split)).collect()),
+            Query::Not(query) => Query::Not(Box::new(query.split_words(split))),
+            Query::Field(field) => Query::Field(field.clone()),
+        }
+    }
+
+    /// Every word in the query, including those which are negated.
+    pub fn words(&self) -> Vec<String> {
+        match self {
+            Query::Not(query) => query.words(),
+            Query::And(queries) | Query::Or(queries) => queries.iter().flat_map(Query::words).collect(),
+            query => query.terms(),
+        }
+    }
+
+    /// The words a matching note may contain, leaving out those which are negated.
+    pub fn terms(&self) -> Vec<String> {
+        match self {
+            Query::Term(word) => vec![word.clone()],
+            Query::Phrase(words) => words.clone(),
+            Query::Near(left, right, _) => left.iter().chain(right).cloned().collect(),
+            Query::And(queries) | Query::Or(queries) => queries.iter().flat_map(Query::terms).collect(),
+            Query::Not(_) | Query::Field(_) => Vec::new(),
+        }
+    }
+
+    /// Every `field:value` term in the query, including those which are negated.
+    pub fn fields(&self) -> Vec<&Field> {
+        match self {
+            Query::Field(field) => vec![field],
+            Query::Not(query) => query.fields(),
+            Query::And(queries) | Query::Or(queries) => queries.iter().flat_map(Query::fields).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    And,
+    Or,
+    Not,
+    Near(u32),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{word}"),
+            Token::Quoted(text) => write!(f, "\"{text}\""),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Near(distance) => write!(f, "NEAR/{distance}"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | '&' | '|')
+}
+
+/// Splits a search into tokens. Only upper case `AND`, `OR` and `NOT` are operators,
+/// so that "and", "or" and "not" can still be searched for.
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {},
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '&' => tokens.push(Token::And),
+            '|' => tokens.push(Token::Or),
+            '"' => {
+                // An unterminated quote runs to the end of the search.
+                let quoted: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                tokens.push(Token::Quoted(quoted));
+            },
+            '-' if chars.peek().is_some_and(|&next| !is_special(next) || next == '"' || next == '(') => {
+                tokens.push(Token::Not);
+            },
+            c => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if is_special(next) { break }
+                    word.push(next);
+                    chars.next();
+                }
+                // A quoted value belongs to its field, as in `after:"3 days ago"`.
+                if word.ends_with(':') && chars.peek() == Some(&'"') {
+                    chars.next();
+                    word.extend(chars.by_ref().take_while(|&c| c != '"'));
+                }
+                let near_distance = match word.strip_prefix("NEAR") {
+                    Some("") => Some(DEFAULT_NEAR_DISTANCE),
+                    Some(distance) => distance.strip_prefix('/').and_then(|distance| distance.parse().ok()),
+                    None => None,
+                };
+                tokens.push(match (word.as_str(), near_distance) {
+                    (_, Some(distance)) => Token::Near(distance),
+                    ("AND", _) => Token::And,
+                    ("OR", _) => Token::Or,
+                    ("NOT", _) => Token::Not,
+                    _ => Token::Word(word),
+                });
+            },
+        }
+    }
+
+    tokens
+}
+
+fn words_of(query: Query) -> Option<Vec<String>> {
+    match query {
+        Query::Term(word) => Some(vec![word]),
+        Query::Phrase(words) => Some(words),
+        _ => None,
+    }
+}
+
+fn parse_error(problem: &str) -> KprError {
+    KprError::new(&format!("Could not understand the search: {problem}"))
+}
+
+/// A recursive descent parser for `or = and (OR and)*`, `and = near (AND? near)*`,
+/// `near = unary (NEAR/n unary)?` and `unary = NOT unary | field:value | word | "quoted" | ( or )`.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Query, KprError> {
+        let mut queries = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            queries.push(self.parse_and()?);
+        }
+        Ok(if queries.len() == 1 { queries.remove(0) } else { Query::Or(queries) })
+    }
+
+    fn parse_and(&mut self) -> Result<Query, KprError> {
+        let mut queries = vec![self.parse_near()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => { self.next(); },
+                Some(Token::Word(_) | Token::Quoted(_) | Token::Not | Token::Open) => {},
+                _ => break,
+            }
+            queries.push(self.parse_near()?);
+        }
+        Ok(if queries.len() == 1 { queries.remove(0) } else { Query::And(queries) })
+    }
+
+    fn parse_near(&mut self) -> Result<Query, KprError> {
+        let left = self.parse_unary()?;
+        let distance = match self.peek() {
+            Some(&Token::Near(distance)) => distance,
+            _ => return Ok(left),
+        };
+        self.next();
+        let right = self.parse_unary()?;
+
+        match (words_of(left), words_of(right)) {
+            (Some(left), Some(right)) => Ok(Query::Near(left, right, distance)),
+            _ => Err(parse_error("NEAR can only join words or \"quoted phrases\"")),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, KprError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Word(word)) => match Field::parse(&word)? {
+                Some(field) => Ok(Query::Field(field)),
+                None => Ok(Query::Term(word)),
+            },
+            Some(Token::Quoted(text)) => Ok(Query::Phrase(text.split_whitespace().map(ToString::to_string).collect())),
+            Some(Token::Open) => {
+                let query = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(query),
+                    _ => Err(parse_error("missing ')'")),
+                }
+            },
+            Some(token) => Err(parse_error(&format!("unexpected '{token}'"))),
+            None => Err(parse_error("expected a word at the end")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(word: &str) -> Query {
+        Query::Term(word.to_string())
+    }
+
+    fn parse(text: &str) -> Query {
+        Query::parse(text).expect("Could not parse query").expect("Query is empty")
+    }
+
+    #[test]
+    fn test_words_are_anded() {
+        assert_eq!(parse("deploy api"), Query::And(vec![term("deploy"), term("api")]));
+        assert_eq!(parse("deploy AND api"), parse("deploy & api"));
+    }
+
+    #[test]
+    fn test_negation() {
+        assert_eq!(parse("deploy -staging"), Query::And(vec![term("deploy"), Query::Not(Box::new(term("staging")))]));
+        assert_eq!(parse("deploy NOT staging"), parse("deploy -staging"));
+        assert_eq!(parse("e-mail"), term("e-mail"));
+    }
+
+    #[test]
+    fn test_precedence_and_parentheses() {
+        assert_eq!(
+            parse("a b | c"),
+            Query::Or(vec![Query::And(vec![term("a"), term("b")]), term("c")])
+        );
+        assert_eq!(
+            parse("a (b OR c)"),
+            Query::And(vec![term("a"), Query::Or(vec![term("b"), term("c")])])
+        );
+        assert_eq!(parse("(a|b)"), Query::Or(vec![term("a"), term("b")]));
+    }
+
+    #[test]
+    fn test_quoted_words_are_not_operators() {
+        assert_eq!(parse("\"OR\" -\"disk full\""), Query::And(vec![
+            Query::Phrase(vec!["OR".to_string()]),
+            Query::Not(Box::new(Query::Phrase(vec!["disk".to_string(), "full".to_string()]))),
+        ]));
+    }
+
+    #[test]
+    fn test_near() {
+        let disk = vec!["disk".to_string()];
+        let full = vec!["full".to_string()];
+        assert_eq!(parse("disk NEAR/3 full"), Query::Near(disk.clone(), full.clone(), 3));
+        assert_eq!(parse("disk NEAR full"), Query::Near(disk, full, DEFAULT_NEAR_DISTANCE));
+        assert_eq!(parse("NEARBY"), term("NEARBY"));
+        assert!(Query::parse("(a | b) NEAR c").is_err());
+        assert!(Query::parse("a NEAR").is_err());
+    }
+
+    #[test]
+    fn test_terms_leave_out_negated_words() {
+        assert_eq!(parse("(deploy | ship) -staging").terms(), vec!["deploy", "ship"]);
+        assert_eq!(parse("(deploy | ship) -staging").words(), vec!["deploy", "ship", "staging"]);
+    }
+
+    #[test]
+    fn test_split_words() {
+        let split = |word: &str| -> Vec<String> {
+            word.split('-').filter(|part| !part.is_empty()).map(ToString::to_string).collect()
+        };
+        let phrase = |words: &[&str]| Query::Phrase(words.iter().map(ToString::to_string).collect());
+
+        assert_eq!(parse("db-1").split_words(&split), phrase(&["db", "1"]));
+        assert_eq!(parse("deploy -db-1").split_words(&split), Query::And(vec![term("deploy"), Query::Not(Box::new(phrase(&["db", "1"])))]));
+        assert_eq!(parse("\"on db-1\"").split_words(&split), phrase(&["on", "db", "1"]));
+        assert_eq!(parse("...").split_words(&split), term("..."));
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(parse("tag:#OnCall"), Query::Field(Field::Tag("oncall".to_string())));
+        assert_eq!(parse("deploy -id:AB12"), Query::And(vec![term("deploy"), Query::Not(Box::new(Query::Field(Field::Id("ab12".to_string()))))]));
+        assert_eq!(parse("len:>200"), Query::Field(Field::Length(201..=usize::MAX)));
+        assert_eq!(parse("len:<=3"), Query::Field(Field::Length(0..=3)));
+        assert_eq!(parse("len:40"), Query::Field(Field::Length(40..=40)));
+        assert!(matches!(parse("len:<0"), Query::Field(Field::Length(lengths)) if lengths.is_empty() && !lengths.contains(&0)));
+        assert!(matches!(parse(&format!("len:>{}", usize::MAX)), Query::Field(Field::Length(lengths)) if lengths.is_empty()));
+        assert!(matches!(parse("after:\"3 days ago\" deploy"), Query::And(queries) if matches!(queries[0], Query::Field(Field::After(_)))));
+        assert_eq!(parse("http://example.com"), term("http://example.com"));
+        assert_eq!(parse("\"tag:oncall\""), Query::Phrase(vec!["tag:oncall".to_string()]));
+        assert_eq!(parse("tag:oncall deploy").fields().len(), 1);
+        assert!(parse("tag:oncall deploy").terms() == vec!["deploy"]);
+
+        assert!(Query::parse("tag:").is_err());
+        assert!(Query::parse("len:>lots").is_err());
+        assert!(Query::parse("len:~5").is_err());
+        assert!(Query::parse("before:someday").is_err());
+        assert!(Query::parse("tag:a NEAR b").is_err());
+    }
+
+    #[test]
+    fn test_fields_match_metadata() {
+        let record = Record::new("ab12cd34".to_string(), crate::dates::parse_period("2026-10-05 12:00").expect("Could not parse date").start, "db down #oncall".to_string());
+        let matches = |text: &str| match parse(text) {
+            Query::Field(field) => field.matches(&record),
+            query => panic!("Expected a field, not {query:?}"),
+        };
+
+        assert!(matches("tag:oncall") && !matches("tag:deploy"));
+        assert!(matches("id:ab1") && !matches("id:cd"));
+        assert!(matches("len:15") && matches("len:>14") && !matches("len:>15") && matches("len:<16"));
+        assert!(matches("date:2026-10") && matches("date:2026-10-05") && !matches("date:2026-10-06"));
+        assert!(matches("date:2026") && !matches("date:2025") && !matches("after:2026"));
+        assert!(matches("before:2026-10-06") && !matches("before:2026-10-05"));
+        assert!(matches("after:2026-10-04") && !matches("after:2026-10-05"));
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        assert!(Query::parse("").expect("Could not parse query").is_none());
+        assert!(Query::parse("(deploy").is_err());
+        assert!(Query::parse("deploy)").is_err());
+        assert!(Query::parse("deploy OR").is_err());
+        assert!(Query::parse("| deploy").is_err());
+    }
+}
@@ -1,50 +1,300 @@
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::collections::HashSet;
 
+use chrono::{DateTime, Local, TimeZone};
+
+use crate::helpers::fnv1a;
+
+#[derive(Clone)]
 pub struct Record {
+    pub id: String,
     pub timestamp: DateTime<Local>,
     pub message: String,
+    /// The `#tags` in the message, lowercased and without the `#`.
+    pub tags: Vec<String>,
 }
 
 impl Record {
-    pub fn new(timestamp: DateTime<Local>, message: String) -> Self {
+    pub fn new(id: String, timestamp: DateTime<Local>, message: String) -> Self {
+        let tags = extract_tags(&message);
         Record {
+            id,
             timestamp,
             message,
+            tags,
         }
     }
-    
+
+    pub fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.tags.contains(tag))
+    }
+
     pub fn create(message: String) -> Self {
         let timestamp = Local::now();
-        Record::new(timestamp, message)
+        let id = Self::make_id(timestamp.timestamp_millis(), &message);
+        Record::new(id, timestamp, message)
+    }
+
+    pub fn to_store(&self) -> String {
+        format!("{} {}: {}", self.timestamp.timestamp_millis(), self.id, escape(&self.message))
+    }
+
+    /// This record, with its ID re-salted until it isn't one of `taken`. IDs are short, so two
+    /// notes can hash to the same one, and identical notes kept in the same millisecond always do.
+    pub fn with_unique_id(&self, taken: &HashSet<String>) -> Self {
+        let ms_since_epoch = self.timestamp.timestamp_millis();
+        let mut record = self.clone();
+        let mut salt = 0;
+        while taken.contains(&record.id) {
+            salt += 1;
+            record.id = Self::make_id(ms_since_epoch, &format!("{}:{salt}", self.message));
+        }
+        record
     }
 
+    /// A short, stable identifier: the FNV-1a hash of the timestamp and message, folded to 32 bits.
+    fn make_id(ms_since_epoch: i64, message: &str) -> String {
+        let hash = fnv1a(format!("{ms_since_epoch}:{message}").as_bytes());
+
+        format!("{:08x}", (hash >> 32) as u32 ^ hash as u32)
+    }
+}
+
+/// One line of the store. Notes are never changed in place: edits and deletions are
+/// appended as new entries which refer back to the note by its ID.
+pub enum Entry {
+    Note(Record),
+    /// A replacement message for the note with the same ID.
+    Edit(Record),
+    /// A tombstone for the note with this ID.
+    Delete { id: String, timestamp: DateTime<Local> },
+}
+
+impl Entry {
     pub fn from_store(line: &str) -> Option<Self> {
-        
+
         if line.trim().is_empty() {
             return None
         }
-        
-        let mut parts = line.splitn(2, ": ");
-    
-        let ms_since_epoch = parts.next()?.trim().parse::<u128>().ok()?;
-        let timestamp = Self::datetime_from_epoch(ms_since_epoch)?;
-        
-        let message = parts.next()?.trim().to_string();
-        
-        Some(Record::new(timestamp, message))
+
+        let (header, message) = line.split_once(':')?;
+        let mut header = header.split_whitespace();
+
+        let ms_since_epoch = header.next()?.parse::<i64>().ok()?;
+        let timestamp = datetime_from_epoch(ms_since_epoch)?;
+
+        // Only the space after the colon and the line ending are dropped, so a message keeps its
+        // indentation and any trailing spaces.
+        let message = message.strip_prefix(' ').unwrap_or(message);
+        let message = message.strip_suffix('\n').unwrap_or(message);
+        let message = message.strip_suffix('\r').unwrap_or(message);
+
+        // Lines written before notes had IDs are given one derived from their content,
+        // so the same line always gets the same ID. They also predate escaping, so are read as-is.
+        let (id, message) = match header.next() {
+            Some(id) => (id.to_string(), unescape(message)),
+            None => (Record::make_id(ms_since_epoch, message), message.to_string()),
+        };
+
+        match header.next() {
+            None => Some(Entry::Note(Record::new(id, timestamp, message))),
+            Some("edit") => Some(Entry::Edit(Record::new(id, timestamp, message))),
+            Some("rm") => Some(Entry::Delete { id, timestamp }),
+            Some(_) => None,
+        }
+    }
+
+    /// The ID of the note this entry is, or changes.
+    pub fn id(&self) -> &str {
+        match self {
+            Entry::Note(record) | Entry::Edit(record) => &record.id,
+            Entry::Delete { id, .. } => id,
+        }
     }
 
     pub fn to_store(&self) -> String {
-        format!("{}: {}", self.timestamp.timestamp_millis(), self.message)
+        match self {
+            Entry::Note(record) => record.to_store(),
+            Entry::Edit(record) => format!("{} {} edit: {}", record.timestamp.timestamp_millis(), record.id, escape(&record.message)),
+            Entry::Delete { id, timestamp } => format!("{} {id} rm:", timestamp.timestamp_millis()),
+        }
     }
+}
+
+/// Finds words like `#deploy` or `#on-call`. The tag ends at the first character which
+/// isn't alphanumeric, `-` or `_`, so `#deploy,` is tagged `deploy`.
+fn extract_tags(message: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+
+    for word in message.split_whitespace() {
+        // Allow for opening punctuation, as in "(#oncall)".
+        let word = word.trim_start_matches(|c: char| !c.is_alphanumeric() && c != '#');
+        let tag = match word.strip_prefix('#') {
+            Some(rest) => normalize_tag(rest),
+            None => continue,
+        };
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    tags
+}
+
+/// Lowercases a tag and drops any leading `#`, so `-t Deploy` and `-t '#deploy'` are the same.
+pub fn normalize_tag(tag: &str) -> String {
+    tag
+        .trim_start_matches('#')
+        .chars()
+        .take_while(|&c| c.is_alphanumeric() || c == '-' || c == '_')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn datetime_from_epoch(ms: i64) -> Option<DateTime<Local>> {
+    Local.timestamp_millis_opt(ms).single()
+}
 
-    fn datetime_from_epoch(ms: u128) -> Option<DateTime<Local>> {
-        let utc_datetime = NaiveDateTime::from_timestamp_opt((ms / 1000) as i64, 0)?;
-        let datetime = Local.from_utc_datetime(&utc_datetime);
-        Some(datetime)
+/// Escapes a message so that it fits on one line of the store.
+fn escape(message: &str) -> String {
+    message
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(message: &str) -> String {
+    let mut unescaped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => { unescaped.push('\\'); unescaped.push(other); },
+            None => unescaped.push('\\'),
+        }
     }
+
+    unescaped
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(line: &str) -> Option<Record> {
+        match Entry::from_store(line)? {
+            Entry::Note(record) => Some(record),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let record = Record::create("a message: with a colon".to_string());
+        let parsed = note(&record.to_store()).expect("Could not parse record");
+
+        assert_eq!(parsed.id, record.id);
+        assert_eq!(parsed.timestamp.timestamp_millis(), record.timestamp.timestamp_millis());
+        assert_eq!(parsed.message, record.message);
+    }
+
+    #[test]
+    fn test_legacy_line_gets_deterministic_id() {
+        let line = "1680917693908: message two";
+        let first = note(line).expect("Could not parse record");
+        let second = note(line).expect("Could not parse record");
+
+        assert_eq!(first.id.len(), 8);
+        assert_eq!(first.id, second.id);
+        assert_eq!(first.message, "message two");
+
+        let other = note("1680917693908: message three").expect("Could not parse record");
+        assert_ne!(first.id, other.id);
+    }
+
+    #[test]
+    fn test_legacy_line_keeps_its_id_when_rewritten() {
+        let legacy = note("1680917693908: message two").expect("Could not parse record");
+        let rewritten = note(&legacy.to_store()).expect("Could not parse record");
+
+        assert_eq!(legacy.to_store(), format!("1680917693908 {}: message two", legacy.id));
+        assert_eq!(rewritten.id, legacy.id);
+    }
+
+    #[test]
+    fn test_taken_ids_are_salted() {
+        let record = note("1680917693908 0000abcd: message two").expect("Could not parse record");
+        assert_eq!(record.with_unique_id(&HashSet::new()).id, "0000abcd");
 
+        let mut taken = HashSet::from(["0000abcd".to_string()]);
+        let salted = record.with_unique_id(&taken);
+        assert_ne!(salted.id, "0000abcd");
+        assert_eq!(salted.id.len(), 8);
+        assert_eq!(salted.message, record.message);
 
+        taken.insert(salted.id.clone());
+        assert!(!taken.contains(&record.with_unique_id(&taken).id));
+    }
+
+    #[test]
+    fn test_multi_line_round_trip() {
+        let message = "Traceback:\n    at main.rs:10\r\n\n\\n is not a newline".to_string();
+        let record = Record::create(message.clone());
+        let line = record.to_store();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(note(&line).expect("Could not parse record").message, message);
+
+        let indented = "    at main.rs:10\n    at lib.rs:3".to_string();
+        let line = Record::create(indented.clone()).to_store();
+        assert_eq!(note(&line).expect("Could not parse record").message, indented);
+
+        let spaced = "trailing spaces \t ".to_string();
+        let line = Record::create(spaced.clone()).to_store();
+        assert_eq!(note(&line).expect("Could not parse record").message, spaced);
+        assert_eq!(note(&format!("{line}\r\n")).expect("Could not parse record").message, spaced);
+    }
+
+    #[test]
+    fn test_legacy_line_is_not_unescaped() {
+        let legacy = note(r"1680917693908: C:\new\folder").expect("Could not parse record");
+        assert_eq!(legacy.message, r"C:\new\folder");
+    }
+
+    #[test]
+    fn test_edit_and_delete_round_trip() {
+        let edit = Entry::Edit(Record::create("fixed the typo".to_string()));
+        match Entry::from_store(&edit.to_store()) {
+            Some(Entry::Edit(record)) => assert_eq!(record.message, "fixed the typo"),
+            _ => panic!("Expected an edit entry"),
+        }
+
+        let delete = Entry::Delete { id: "0123abcd".to_string(), timestamp: Local::now() };
+        match Entry::from_store(&delete.to_store()) {
+            Some(Entry::Delete { id, .. }) => assert_eq!(id, "0123abcd"),
+            _ => panic!("Expected a delete entry"),
+        }
+
+        assert!(note(&delete.to_store()).is_none());
+    }
+
+    #[test]
+    fn test_tags_are_extracted() {
+        let record = Record::create("#Deploy of api (#oncall, #deploy) for C# and # headings #on-call".to_string());
+        assert_eq!(record.tags, vec!["deploy", "oncall", "on-call"]);
+        assert!(record.has_tags(&["oncall".to_string(), "deploy".to_string()]));
+        assert!(!record.has_tags(&["oncall".to_string(), "staging".to_string()]));
+    }
+
+    #[test]
+    fn test_blank_line_is_not_a_record() {
+        assert!(note("   ").is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::KprError;
+use crate::store;
+
+pub const SEARCHES_FILENAME: &str = "searches.toml";
+
+/// A search kept with `kpr search --save`, as it was typed, so that relative dates like
+/// `--since "this week"` mean the same thing each time it runs.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SavedSearch {
+    /// The words of the query, each as the shell passed it, so `-E 'a  b'` keeps both spaces.
+    pub query: Vec<String>,
+    /// The options given to `search`, like `-n 5` or `--since yesterday`.
+    #[serde(default)]
+    pub options: Vec<String>,
+    /// The notebook it was saved in, which `kpr run` searches unless `-b` says otherwise.
+    pub notebook: String,
+}
+
+/// The saved searches in the data directory's `searches.toml`, by name.
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(transparent)]
+pub struct SavedSearches(BTreeMap<String, SavedSearch>);
+
+impl SavedSearches {
+    pub fn path(root: &Path) -> PathBuf {
+        root.join(SEARCHES_FILENAME)
+    }
+
+    pub fn load(root: &Path) -> Result<Self, KprError> {
+        let filepath = Self::path(root);
+        let contents = match std::fs::read_to_string(&filepath) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(SavedSearches::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        toml::from_str(&contents)
+            .map_err(|e| KprError::new(&format!("Could not read {}: {e}", filepath.display())))
+    }
+
+    pub fn save(&self, root: &Path) -> Result<(), KprError> {
+        let contents = toml::to_string(self)
+            .map_err(|e| KprError::new(&format!("Could not write saved searches: {e}")))?;
+        std::fs::create_dir_all(root)?;
+        store::write_atomically(&Self::path(root), |file| file.write_all(contents.as_bytes()))?;
+        Ok(())
+    }
+
+    /// Keeps `search` as `name`, replacing any search already called that.
+    pub fn insert(&mut self, name: &str, search: SavedSearch) -> Result<(), KprError> {
+        if name.trim().is_empty() {
+            return Err(KprError::new("A saved search needs a name."));
+        }
+        self.0.insert(name.to_string(), search);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&SavedSearch, KprError> {
+        self.0
+            .get(name)
+            .ok_or_else(|| KprError::new(&format!("No search is saved as '{name}', see kpr saved.")))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&String, &SavedSearch)> {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(query: &str, options: &[&str]) -> SavedSearch {
+        SavedSearch {
+            query: query.split(' ').map(str::to_string).collect(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+            notebook: "work".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_saved_searches_round_trip_through_toml() {
+        let mut searches = SavedSearches::default();
+        searches.insert("incidents", search("#incident -resolved", &["--since", "this week", "-n", "5"])).expect("Could not save");
+        searches.insert("decisions", search("decided", &[])).expect("Could not save");
+
+        let contents = toml::to_string(&searches).expect("Could not serialize searches");
+        let parsed: SavedSearches = toml::from_str(&contents).expect("Could not parse searches");
+        assert_eq!(parsed.get("incidents").expect("Not saved"), searches.get("incidents").expect("Not saved"));
+        let names: Vec<&String> = parsed.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["decisions", "incidents"]);
+    }
+
+    #[test]
+    fn test_saving_again_replaces_the_search() {
+        let mut searches = SavedSearches::default();
+        searches.insert("deploys", search("deploy", &[])).expect("Could not save");
+        searches.insert("deploys", search("deploy -staging", &[])).expect("Could not save");
+        assert_eq!(searches.get("deploys").expect("Not saved").query, vec!["deploy", "-staging"]);
+        assert!(searches.get("other").is_err());
+        assert!(searches.insert(" ", search("deploy", &[])).is_err());
+    }
+}
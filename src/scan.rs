@@ -0,0 +1,98 @@
+use std::ops::Range;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::cli::{SearchArgs, SortOrder};
+use crate::config;
+use crate::errors::KprError;
+use crate::formatters::highlight;
+use crate::records::Record;
+use crate::search;
+use crate::store;
+
+/// Builds the pattern for a `--regex` or `--literal` search.
+pub fn pattern(text: &str, literal: bool, ignore_case: bool) -> Result<Regex, KprError> {
+    let text = if literal { regex::escape(text) } else { text.to_string() };
+    RegexBuilder::new(&text)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| KprError::new(&format!("Could not understand the regex: {e}")))
+}
+
+/// Finds the notes whose messages match `pattern`, newest first (or oldest first, with
+/// `--sort oldest`), by reading the store itself rather than the index, so punctuation and case
+/// are kept. Reading newest first stops after `-n` matches.
+pub fn scan(pattern: &Regex, args: &SearchArgs) -> Vec<Record> {
+    let n = args.n.unwrap_or(config::settings().count);
+    let matches = |record: &Record| {
+        record.has_tags(&args.tags) && args.dates.contains(record.timestamp) && pattern.is_match(&record.message)
+    };
+
+    if args.sort == SortOrder::Oldest {
+        // Later edits can change whether an old note matches, so the whole store is replayed.
+        return store::replay(store::load_lines(None))
+            .into_iter()
+            .map(|(_, record)| record)
+            .filter(matches)
+            .take(n)
+            .collect();
+    }
+
+    let since = args.dates.earliest().filter(|_| search::store_is_in_date_order());
+    let mut records = store::load_records(Some(n), since, matches);
+    records.reverse();
+    records
+}
+
+/// Colours the parts of a message which match `pattern`, cutting long messages down to a
+/// snippet of `snippet_width` words.
+pub fn make_span_formatter(pattern: Regex, snippet_width: Option<usize>) -> impl Fn(&String) -> String {
+    move |message: &String| {
+        // Empty matches, like those of `a*`, have nothing to colour.
+        let matches: Vec<Range<usize>> = pattern
+            .find_iter(message)
+            .filter(|found| !found.is_empty())
+            .map(|found| found.range())
+            .collect();
+        highlight(message, &matches, snippet_width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use colored::Colorize;
+
+    use super::*;
+
+    #[test]
+    fn test_literal_patterns_keep_punctuation() {
+        let pattern = pattern("foo_bar()", true, false).expect("Could not build pattern");
+        assert!(pattern.is_match("called foo_bar() twice"));
+        assert!(!pattern.is_match("called foo_bar twice"));
+        assert!(!pattern.is_match("called FOO_BAR() twice"));
+    }
+
+    #[test]
+    fn test_case() {
+        let pattern = pattern(r"10\.0\.\d+\.\d+|ERR", false, true).expect("Could not build pattern");
+        assert!(pattern.is_match("db-1 is at 10.0.3.17"));
+        assert!(pattern.is_match("err: disk full"));
+        assert!(!pattern.is_match("10.1.3.17"));
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(pattern("(unclosed", false, false).is_err());
+        assert!(pattern("(unclosed", true, false).is_ok());
+    }
+
+    #[test]
+    fn test_span_formatter_colours_matches() {
+        let highlight = make_span_formatter(pattern("a*b", false, false).expect("Could not build pattern"), None);
+        let message = "xaab c b".to_string();
+        assert_eq!(highlight(&message), format!("x{} c {}", "aab".green(), "b".green()));
+
+        let highlight = make_span_formatter(pattern("z*", false, false).expect("Could not build pattern"), None);
+        assert_eq!(highlight(&message), message);
+    }
+}
@@ -1,100 +1,541 @@
-use std::collections::{HashSet, HashMap};
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter};
-use std::path::PathBuf;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet, HashMap};
+use std::ops::{Bound, Range};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
 
-use bincode;
 extern crate rust_stemmers;
 use rust_stemmers::{Algorithm, Stemmer};
-use colored::Colorize;
+use chrono::{Duration, Local};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-use crate::records::Record;
-use crate::store::{full_path, open_read, self};
-use crate::store::open_or_create;
+use crate::cli::{SearchArgs, SortOrder};
+use crate::config;
+use crate::formatters::highlight;
+use crate::fuzzy::{max_typos, BkTree};
+use crate::errors::KprError;
+use crate::notebooks;
+use crate::query::{Field, Query, WILDCARD};
+use crate::records::{Entry, Record};
+use crate::helpers::fnv1a;
+use crate::store::{full_path, self, Fingerprint, LineNumber};
+use crate::tokens::{search_words, tokenize};
 
 
 
 const INDEX_FILENAME: &str = "index.txt";
+
+/// Index files start with these bytes and then the format version, as a little-endian `u32`.
+const INDEX_MAGIC: &[u8; 4] = b"KPRI";
+const INDEX_VERSION: u32 = 11;
 const STOPS_FILENAME: &str = "stopwords.txt";
 
-pub fn search(query: &[String], n: usize) -> Vec<Record> {
-    let index = Index::load();
-    let result_indexes = index.search(query);
-    if result_indexes.is_empty() {
-        return Vec::new();
+/// Tags share the index with word stems, but are keyed with a leading `#`.
+/// Stems never start with `#` because tokens never include punctuation.
+const TAG_PREFIX: char = '#';
+
+/// What a search found.
+pub struct Found {
+    pub records: Vec<Record>,
+    /// The stems the query's words matched, to highlight.
+    pub stems: HashSet<String>,
+    /// Words from the notes which are close to the query's, when nothing matched.
+    pub suggestions: Vec<String>,
+}
+
+pub fn search(query: Option<&Query>, args: &SearchArgs) -> Result<Found, KprError> {
+    let index = Index::load()?;
+    let tags = &args.tags;
+    let notes = store::replay(store::load_lines(None));
+
+    let (scores, stems) = if let Some(query) = query {
+        let tagged: HashSet<LineNumber> = index.lookup_tags(tags).into_iter().collect();
+        let (mut scores, stems) = index.scores(query, args.fuzzy, &notes);
+        if !tags.is_empty() {
+            scores.retain(|(line_number, _)| tagged.contains(line_number));
+        }
+        (scores, stems)
+    } else {
+        // Only filtering by tag or date, so every match is as relevant as the others.
+        let lines: Vec<LineNumber> = if tags.is_empty() {
+            index.all_lines().into_iter().collect()
+        } else {
+            index.lookup_tags(tags)
+        };
+        (lines.into_iter().map(|line_number| (line_number, 1.0)).collect(), HashSet::new())
+    };
+
+    let mut records: HashMap<LineNumber, Record> = notes.into_iter().collect();
+
+    let suggestions = match query {
+        Some(query) if scores.is_empty() => index.surface_words(&index.suggestions(query), records.values()),
+        _ => Vec::new(),
+    };
+
+    let now = Local::now();
+    let mut scored: Vec<(LineNumber, f64, Record)> = scores
+        .into_iter()
+        .filter_map(|(line_number, score)| Some((line_number, score, records.remove(&line_number)?)))
+        .filter(|(_, _, record)| args.dates.contains(record.timestamp))
+        .map(|(line_number, score, record)| {
+            let boost = if args.recent { recency_boost(now - record.timestamp) } else { 1.0 };
+            (line_number, score * boost, record)
+        })
+        .collect();
+
+    match args.sort {
+        SortOrder::Relevance => sort_by_score(&mut scored, |&(line_number, score, _)| (line_number, score)),
+        SortOrder::Newest => scored.sort_by_key(|(line_number, _, record)| std::cmp::Reverse((record.timestamp, *line_number))),
+        SortOrder::Oldest => scored.sort_by_key(|(line_number, _, record)| (record.timestamp, *line_number)),
     }
 
-    let lines = store::load_lines(None);
-    result_indexes
+    let n = args.n.unwrap_or(config::settings().count);
+    let records = scored.into_iter().take(n).map(|(_, _, record)| record).collect();
+    Ok(Found { records, stems, suggestions })
+}
+
+/// The IDs of the notes matching `query`, for `list` to show in date order, or none if the query
+/// doesn't rule any notes out. Unlike `search`, misspelled words don't fall back to fuzzy matches,
+/// since `list` has no ranking to put them last.
+pub fn matching_ids(query: &Query) -> Result<Option<HashSet<String>>, KprError> {
+    let index = Index::load()?;
+    let notes = store::replay(store::load_lines(None));
+    let (lines, _) = index.filter(query, &notes);
+    Ok(lines.map(|lines| {
+        notes.into_iter().filter(|(line_number, _)| lines.contains(line_number)).map(|(_, record)| record.id).collect()
+    }))
+}
+
+/// The `n` notes most like `note`, most similar first.
+pub fn related(note: &str, n: usize) -> Result<Found, KprError> {
+    let (line_number, _) = store::find(note)?;
+    let index = Index::load()?;
+    let (scores, stems) = index.related(line_number);
+
+    let mut records: HashMap<LineNumber, Record> = store::replay(store::load_lines(None)).into_iter().collect();
+    let records = scores
         .into_iter()
-        .filter_map(|line_number| lines.get(line_number as usize))
+        .filter_map(|(line_number, _)| records.remove(&line_number))
         .take(n)
-        .filter_map(|line| Record::from_store(line))
-        .collect()
+        .collect();
+    Ok(Found { records, stems, suggestions: Vec::new() })
 }
 
+/// Up to doubles the relevance of new notes, falling by half every 30 days.
+fn recency_boost(age: Duration) -> f64 {
+    const HALF_LIFE_DAYS: f64 = 30.0;
+    let age_days = age.num_seconds().max(0) as f64 / (24.0 * 60.0 * 60.0);
+    1.0 + 0.5f64.powf(age_days / HALF_LIFE_DAYS)
+}
+
+/// Whether the store's entries are known to be in date order, which the index's fingerprint of
+/// the store says if it is up to date. Only the start of the index is read, and the store only
+/// when its length hasn't changed, so this is cheap enough to check before reading the store
+/// newest first.
+pub fn store_is_in_date_order() -> bool {
+    let Ok(file) = File::open(full_path(INDEX_FILENAME)) else { return false };
+    let mut reader = BufReader::new(file);
+
+    let mut header = [0; 8];
+    if reader.read_exact(&mut header).is_err() || Index::split_header(&header).0 != INDEX_VERSION {
+        return false;
+    }
+    // The fingerprint comes first in the index file.
+    match bincode::deserialize_from::<_, Fingerprint>(reader) {
+        Ok(fingerprint) => fingerprint.in_date_order && fingerprint.matches_store().unwrap_or(false),
+        Err(_) => false,
+    }
+}
 
-pub fn make_match_formatter(query: &[String]) -> impl Fn(&String) -> String {
+
+/// Colours the words of a message whose stems are in `stems`, cutting long messages down to a
+/// snippet of `snippet_width` words.
+pub fn make_match_formatter(stems: HashSet<String>, snippet_width: Option<usize>) -> impl Fn(&String) -> String {
     let stemmer = new_stemmer();
-    let query_stems: HashSet<_> = query.iter().map(|query_word| stemmer.stem(query_word).to_string().to_lowercase()).collect();
 
     move |message: &String| {
-        message.split(' ').map(|word| {
-            let word_stem = stemmer.stem(&word).to_lowercase();
-            if query_stems.contains(&word_stem) {
-                return word.to_string().green().to_string()
-            }
-            word.to_string()
-        }).collect::<Vec<String>>().join(" ")
+        let matches: Vec<Range<usize>> = tokenize(message)
+            .into_iter()
+            .filter(|token| stems.contains(stemmer.stem(&Index::clean(token.text)).as_ref()))
+            .map(|token| token.span)
+            .collect();
+        highlight(message, &matches, snippet_width)
+    }
+}
+
+/// The notes matching part of a query. Negations are kept as the notes to leave out,
+/// so that the set of every note is only worked out if the whole query needs it.
+enum Matches {
+    All,
+    Only(HashSet<LineNumber>),
+    AllBut(HashSet<LineNumber>),
+}
+
+impl Matches {
+    fn and(self, other: Matches) -> Matches {
+        match (self, other) {
+            (Matches::All, matches) | (matches, Matches::All) => matches,
+            (Matches::Only(a), Matches::Only(b)) => Matches::Only(a.intersection(&b).copied().collect()),
+            (Matches::Only(a), Matches::AllBut(b)) | (Matches::AllBut(b), Matches::Only(a)) => {
+                Matches::Only(a.difference(&b).copied().collect())
+            },
+            (Matches::AllBut(a), Matches::AllBut(b)) => Matches::AllBut(a.union(&b).copied().collect()),
+        }
+    }
+
+    fn or(self, other: Matches) -> Matches {
+        match (self, other) {
+            (Matches::All, _) | (_, Matches::All) => Matches::All,
+            (Matches::Only(a), Matches::Only(b)) => Matches::Only(a.union(&b).copied().collect()),
+            (Matches::Only(a), Matches::AllBut(b)) | (Matches::AllBut(b), Matches::Only(a)) => {
+                Matches::AllBut(b.difference(&a).copied().collect())
+            },
+            (Matches::AllBut(a), Matches::AllBut(b)) => Matches::AllBut(a.intersection(&b).copied().collect()),
+        }
+    }
+
+    fn not(self) -> Matches {
+        match self {
+            Matches::All => Matches::Only(HashSet::new()),
+            Matches::Only(lines) => Matches::AllBut(lines),
+            Matches::AllBut(lines) => Matches::Only(lines),
+        }
     }
 }
 
 pub struct Index {
-    index: HashMap<String, Vec<u16>>,
+    index: Postings,
+    /// The words of the notes as they were written, in order, so that words with wildcards can
+    /// be expanded to the stems of the words they match.
+    words: Words,
+    /// The stems in `index` as a BK-tree for fuzzy matching, built the first time it is needed.
+    fuzzy_vocabulary: OnceCell<BkTree>,
+    /// How many words each note has, for relevance scoring.
+    lengths: Lengths,
+    /// The store this index was built from.
+    fingerprint: Fingerprint,
+    language: Language,
     stop_words: HashSet<String>,
     stemmer: Stemmer,
 }
 
+/// Where a word stem or tag appears: a note's line, and the positions of the word in its message.
+/// Tags have no positions.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+struct Posting {
+    line: LineNumber,
+    positions: Vec<u32>,
+}
+
+type Postings = HashMap<String, Vec<Posting>>;
+
+type Lengths = HashMap<LineNumber, u32>;
+
+/// A word as it appears in notes, cleaned but not stemmed.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+struct Word {
+    stem: String,
+    /// How many notes have the word, so that it is forgotten when the last of them is.
+    notes: u32,
+}
+
+type Words = BTreeMap<String, Word>;
+
+/// What the index's keys depend on, besides the notes. An index built with other settings is rebuilt.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+struct Analysis {
+    language: Language,
+    /// A hash of the stop words, which aren't indexed.
+    stop_words: u64,
+}
+
+/// What the index file holds.
+type Stored = (Fingerprint, Analysis, Postings, Lengths, Words);
+
+fn lines_of(postings: &[Posting]) -> Vec<LineNumber> {
+    postings.iter().map(|posting| posting.line).collect()
+}
+
+/// The positions of a word on `line_number`.
+fn positions_on(postings: &[Posting], line_number: LineNumber) -> &[u32] {
+    match postings.binary_search_by_key(&line_number, |posting| posting.line) {
+        Ok(i) => &postings[i].positions,
+        Err(_) => &[],
+    }
+}
+
+/// The first and last positions of each place a phrase appears, by line.
+type Spans = HashMap<LineNumber, Vec<(u32, u32)>>;
+
+/// The postings of each word of a query, after expanding the words to the stems they match.
+#[derive(Default)]
+struct Lookup {
+    postings: HashMap<String, Vec<Posting>>,
+    /// The stems matched by the words which aren't negated, for highlighting.
+    stems: HashSet<String>,
+    /// The lines matched by each `field:value` term.
+    fields: Vec<(Field, HashSet<LineNumber>)>,
+}
+
+impl Lookup {
+    fn postings(&self, word: &str) -> &[Posting] {
+        self.postings.get(word).map_or(&[], Vec::as_slice)
+    }
+
+    fn field_lines(&self, field: &Field) -> HashSet<LineNumber> {
+        self.fields.iter().find(|(other, _)| other == field).map(|(_, lines)| lines.clone()).unwrap_or_default()
+    }
+}
+
+/// Combines the postings of several stems, as if they were one.
+fn merge_postings<'a>(postings: impl IntoIterator<Item=&'a Vec<Posting>>) -> Vec<Posting> {
+    let mut merged: BTreeMap<LineNumber, Vec<u32>> = BTreeMap::new();
+    for posting in postings.into_iter().flatten() {
+        merged.entry(posting.line).or_default().extend(&posting.positions);
+    }
+
+    merged
+        .into_iter()
+        .map(|(line, mut positions)| {
+            positions.sort_unstable();
+            positions.dedup();
+            Posting { line, positions }
+        })
+        .collect()
+}
+
+fn is_pattern(word: &str) -> bool {
+    word.contains(WILDCARD)
+}
+
+/// Whether `text` matches `pattern`, where each wildcard stands for any number of characters.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split(WILDCARD);
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else { return false };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else { return rest.is_empty() };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Sorts scored lines by the score `key` gives each, highest first, breaking ties with the newest first.
+fn sort_by_score<T>(scored: &mut [T], key: impl Fn(&T) -> (LineNumber, f64)) {
+    scored.sort_by(|a, b| {
+        let ((line_a, score_a), (line_b, score_b)) = (key(a), key(b));
+        score_b.total_cmp(&score_a).then(line_b.cmp(&line_a))
+    });
+}
+
+/// Whether any of the `left` spans is within `distance` words of any of the `right` spans.
+fn is_near(left: &[(u32, u32)], right: &[(u32, u32)], distance: u32) -> bool {
+    left.iter().any(|&(left_start, left_end)| {
+        right.iter().any(|&(right_start, right_end)| {
+            let apart = if right_start > left_end {
+                right_start - left_end
+            } else {
+                left_start.saturating_sub(right_end)
+            };
+            apart <= distance
+        })
+    })
+}
+
+/// The languages which words can be stemmed in.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Language {
+    Arabic,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Greek,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Tamil,
+    Turkish,
+}
+
+impl Language {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Language::Arabic => Algorithm::Arabic,
+            Language::Danish => Algorithm::Danish,
+            Language::Dutch => Algorithm::Dutch,
+            Language::English => Algorithm::English,
+            Language::Finnish => Algorithm::Finnish,
+            Language::French => Algorithm::French,
+            Language::German => Algorithm::German,
+            Language::Greek => Algorithm::Greek,
+            Language::Hungarian => Algorithm::Hungarian,
+            Language::Italian => Algorithm::Italian,
+            Language::Norwegian => Algorithm::Norwegian,
+            Language::Portuguese => Algorithm::Portuguese,
+            Language::Romanian => Algorithm::Romanian,
+            Language::Russian => Algorithm::Russian,
+            Language::Spanish => Algorithm::Spanish,
+            Language::Swedish => Algorithm::Swedish,
+            Language::Tamil => Algorithm::Tamil,
+            Language::Turkish => Algorithm::Turkish,
+        }
+    }
+
+    /// The built-in stop words, one per line.
+    fn stop_words(self) -> &'static str {
+        match self {
+            Language::Arabic => include_str!("stopwords/arabic.txt"),
+            Language::Danish => include_str!("stopwords/danish.txt"),
+            Language::Dutch => include_str!("stopwords/dutch.txt"),
+            Language::English => include_str!("stopwords/english.txt"),
+            Language::Finnish => include_str!("stopwords/finnish.txt"),
+            Language::French => include_str!("stopwords/french.txt"),
+            Language::German => include_str!("stopwords/german.txt"),
+            Language::Greek => include_str!("stopwords/greek.txt"),
+            Language::Hungarian => include_str!("stopwords/hungarian.txt"),
+            Language::Italian => include_str!("stopwords/italian.txt"),
+            Language::Norwegian => include_str!("stopwords/norwegian.txt"),
+            Language::Portuguese => include_str!("stopwords/portuguese.txt"),
+            Language::Romanian => include_str!("stopwords/romanian.txt"),
+            Language::Russian => include_str!("stopwords/russian.txt"),
+            Language::Spanish => include_str!("stopwords/spanish.txt"),
+            Language::Swedish => include_str!("stopwords/swedish.txt"),
+            Language::Tamil => include_str!("stopwords/tamil.txt"),
+            Language::Turkish => include_str!("stopwords/turkish.txt"),
+        }
+    }
+}
+
 fn new_stemmer() -> Stemmer {
-    Stemmer::create(Algorithm::English)
+    Stemmer::create(config::settings().language.algorithm())
 }
 
 impl Index {
 
-    pub fn load() -> Self {
-        Index {
-            index: Self::load_index(),
-            stop_words : Self::load_stopwords(),
-            stemmer: new_stemmer(),
+    /// Loads the index, first bringing it up to date with the store if they have drifted apart.
+    /// An index built for another language or other stop words is rebuilt.
+    pub fn load() -> Result<Self, KprError> {
+        let store_bytes = store::read_bytes()?;
+        let empty = Self::empty();
+
+        let (mut index, rebuilt) = match Self::load_index()? {
+            Some((fingerprint, analysis, index, lengths, words)) if analysis == empty.analysis() => (
+                Index { index, words, lengths, fingerprint, ..empty },
+                false,
+            ),
+            _ => (Self::from_store_bytes(&store_bytes), true),
+        };
+
+        if index.sync(&store_bytes) || rebuilt {
+            index.save()?;
         }
+        Ok(index)
     }
 
-    fn from_lines(lines: impl IntoIterator<Item=String>) -> Self {
-        let mut index = Self {
-            index: HashMap::new(),
-            stop_words : Self::load_stopwords(),
-            stemmer: new_stemmer(),
-        };
+    /// Builds the index from scratch from the store on disk.
+    pub fn rebuild() -> Result<Self, std::io::Error> {
+        Ok(Self::from_store_bytes(&store::read_bytes()?))
+    }
 
-        lines.into_iter().enumerate().for_each(|(line_number, line)| {
-            let record = match Record::from_store(&line) {
-                Some(record) => record,
-                None => return,
-            };
-            index.add_line(line_number as u16, &record);
-        });
+    /// An index of no notes, in the notebook's language.
+    fn empty() -> Self {
+        let language = config::settings().language;
+        Self {
+            index: Postings::new(),
+            words: Words::new(),
+            fuzzy_vocabulary: OnceCell::new(),
+            lengths: Lengths::new(),
+            fingerprint: Fingerprint::default(),
+            language,
+            stop_words: Self::load_stopwords(language),
+            stemmer: Stemmer::create(language.algorithm()),
+        }
+    }
+
+    pub fn from_lines(lines: impl IntoIterator<Item=String>) -> Self {
+        let mut index = Self::empty();
+
+        for (line_number, record) in store::replay(lines) {
+            index.add_line(line_number, &record);
+        }
+
+        index
+    }
 
+    fn from_store_bytes(store_bytes: &[u8]) -> Self {
+        let mut index = Self::from_lines(store::lines_of(store_bytes));
+        index.fingerprint = Fingerprint::of(store_bytes);
         index
     }
 
-    pub fn from_store_path(store_filename: impl Into<PathBuf>) -> Self {
-        let store_filename = store_filename.into();
-        let filepath = full_path(store_filename);
-        let file = File::open(filepath).expect("Could not open store file");
-        let reader = BufReader::new(file);
-        let lines = reader.lines().filter_map(Result::ok);
+    /// Catches the index up with `store_bytes`. If lines were only appended since the index was
+    /// built, just those are indexed, otherwise the whole index is rebuilt.
+    /// Returns whether anything changed.
+    fn sync(&mut self, store_bytes: &[u8]) -> bool {
+        let fingerprint = Fingerprint::of(store_bytes);
+        if fingerprint == self.fingerprint {
+            return false;
+        }
+
+        if self.fingerprint.is_prefix_of(store_bytes) {
+            self.add_appended(store_bytes);
+            self.fingerprint = fingerprint;
+        } else {
+            *self = Self::from_store_bytes(store_bytes);
+        }
+        true
+    }
+
+    /// Indexes the lines after those covered by the fingerprint.
+    fn add_appended(&mut self, store_bytes: &[u8]) {
+        let first_new_line = self.fingerprint.lines;
+        let appended = store::lines_of(&store_bytes[self.fingerprint.len as usize..]);
+        let entries: Vec<Entry> = appended.iter().filter_map(|line| Entry::from_store(line)).collect();
+
+        if entries.iter().all(|entry| matches!(entry, Entry::Note(_))) {
+            for (offset, line) in appended.iter().enumerate() {
+                if let Some(Entry::Note(record)) = Entry::from_store(line) {
+                    self.add_line(first_new_line + offset as LineNumber, &record);
+                }
+            }
+            return;
+        }
+
+        // Edits and deletions refer back to earlier notes, so reindex each note they touch
+        // by comparing it before and after the appended lines.
+        let touched: HashSet<String> = entries
+            .into_iter()
+            .map(|entry| match entry {
+                Entry::Note(record) | Entry::Edit(record) => record.id,
+                Entry::Delete { id, .. } => id,
+            })
+            .collect();
+
+        let lines = store::lines_of(store_bytes);
+        let before = store::replay(lines[..first_new_line as usize].to_vec());
+        let after = store::replay(lines);
 
-        Self::from_lines(lines)
+        for (line_number, record) in before.iter().filter(|(_, record)| touched.contains(&record.id)) {
+            self.remove_line(*line_number, record);
+        }
+        for (line_number, record) in after.iter().filter(|(_, record)| touched.contains(&record.id)) {
+            self.add_line(*line_number, record);
+        }
     }
 
     fn clean(word: &str) -> String {
@@ -102,108 +543,561 @@ impl Index {
     }
     
     fn stem(&self, word: &str) -> String {
-        self.stemmer.stem(&word).to_string()
+        self.stemmer.stem(word).to_string()
     }
 
     fn clean_and_stem(&self, word: &str) -> String {
         self.stem(&Self::clean(word))
     }
 
-    fn add_word(&mut self, word: &str, line_number: u16) {
+    /// Records that `key` appears on `line_number`, at `position` if it's a word.
+    fn add_posting(&mut self, key: String, line_number: LineNumber, position: Option<u32>) {
+        if !key.starts_with(TAG_PREFIX) && !self.index.contains_key(&key) {
+            self.fuzzy_vocabulary.take();
+        }
+        let postings = self.index.entry(key).or_default();
+
+        // Postings are kept in line order, so a line's can be found by binary search.
+        let i = match postings.binary_search_by_key(&line_number, |posting| posting.line) {
+            Ok(i) => i,
+            Err(i) => {
+                postings.insert(i, Posting { line: line_number, positions: Vec::new() });
+                i
+            },
+        };
+        postings[i].positions.extend(position);
+    }
+
+    fn add_word(&mut self, word: &str, line_number: LineNumber, position: u32) {
         let stem = self.clean_and_stem(word);
-        let line_numbers = self.index
-            .entry(stem)
-            .or_default();
-        line_numbers.push(line_number);
+        self.add_posting(stem, line_number, Some(position));
+    }
+
+    /// The different words of `message`, cleaned, leaving out stop words.
+    fn words_of(&self, message: &str) -> BTreeSet<String> {
+        tokenize(message)
+            .into_iter()
+            .map(|token| Self::clean(token.text))
+            .filter(|word| !word.is_empty() && !self.stop_words.contains(word))
+            .collect()
     }
 
-    pub fn add_line(&mut self, line_number: u16, record: &Record) {
-        for word in record.message.split_whitespace() {
-            if self.is_stop(word) { continue }
-            self.add_word(word, line_number);
+    pub fn add_line(&mut self, line_number: LineNumber, record: &Record) {
+        let tokens = tokenize(&record.message);
+        self.lengths.insert(line_number, tokens.len() as u32);
+
+        // Stop words aren't indexed, but still take up a position, so phrases can skip over them.
+        for (position, token) in tokens.iter().enumerate() {
+            if self.is_stop(token.text) { continue }
+            self.add_word(token.text, line_number, position as u32);
+        }
+        for word in self.words_of(&record.message) {
+            let stem = self.stem(&word);
+            self.words.entry(word).or_insert(Word { stem, notes: 0 }).notes += 1;
+        }
+        for tag in &record.tags {
+            self.add_posting(format!("{TAG_PREFIX}{tag}"), line_number, None);
         }
     }
 
+    pub fn remove_line(&mut self, line_number: LineNumber, record: &Record) {
+        self.lengths.remove(&line_number);
+        let stems = tokenize(&record.message).into_iter().map(|token| self.clean_and_stem(token.text));
+        let tags = record.tags.iter().map(|tag| format!("{TAG_PREFIX}{tag}"));
+        let keys: Vec<String> = stems.chain(tags).collect();
+
+        for key in keys {
+            if let Some(postings) = self.index.get_mut(&key) {
+                postings.retain(|posting| posting.line != line_number);
+                if postings.is_empty() {
+                    self.index.remove(&key);
+                    self.fuzzy_vocabulary.take();
+                }
+            }
+        }
+
+        for word in self.words_of(&record.message) {
+            if let Some(Word { notes, .. }) = self.words.get_mut(&word) {
+                *notes -= 1;
+                if *notes == 0 {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// The lines which have every one of `tags`.
+    pub fn lookup_tags(&self, tags: &[String]) -> Vec<LineNumber> {
+        let mut postings = tags
+            .iter()
+            .map(|tag| lines_of(self.index.get(&format!("{TAG_PREFIX}{tag}")).map_or(&[], Vec::as_slice)));
+
+        let first: Vec<LineNumber> = match postings.next() {
+            Some(lines) => lines,
+            None => return Vec::new(),
+        };
+
+        postings.fold(first, |lines, other| {
+            let other: HashSet<LineNumber> = other.into_iter().collect();
+            lines.into_iter().filter(|line_number| other.contains(line_number)).collect()
+        })
+    }
+
+    /// Every tag with the number of notes which have it, most used first.
+    pub fn tag_counts(&self) -> Vec<(String, usize)> {
+        let mut counts: Vec<(String, usize)> = self.index
+            .iter()
+            .filter_map(|(key, postings)| Some((key.strip_prefix(TAG_PREFIX)?.to_string(), postings.len())))
+            .collect();
+
+        counts.sort_unstable_by(|(tag_a, count_a), (tag_b, count_b)| count_b.cmp(count_a).then(tag_a.cmp(tag_b)));
+        counts
+    }
+
     fn is_stop(&self, word: &str) -> bool {
         self.stop_words.contains(&Self::clean(word))
     }
 
-    fn lookup_word(&self, word: &str) -> Vec<u16> {
-        let stem = self.clean_and_stem(word);
-        self.index.get(&stem).cloned().unwrap_or_default()
+    /// Stop words and words which are only punctuation are left out of searches.
+    /// Words with wildcards never are, so `*` can match everything.
+    fn is_ignored(&self, word: &str) -> bool {
+        !is_pattern(word) && (self.is_stop(word) || Self::clean(word).is_empty())
     }
 
-    pub fn save(&self) {
+    pub fn save(&self) -> Result<(), std::io::Error> {
         let filepath = full_path(INDEX_FILENAME);
-        let file = open_or_create(filepath, false).expect("Could not create index file");
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, &self.index).expect("Could not serialize index file");
+        store::write_atomically(&filepath, |file| {
+            let mut writer = BufWriter::new(file);
+            writer.write_all(INDEX_MAGIC)?;
+            writer.write_all(&INDEX_VERSION.to_le_bytes())?;
+            let stored = (&self.fingerprint, &self.analysis(), &self.index, &self.lengths, &self.words);
+            bincode::serialize_into(&mut writer, &stored).map_err(std::io::Error::other)?;
+            writer.flush()
+        })
     }
 
-    fn load_index() -> HashMap<String, Vec<u16>> {
+    fn analysis(&self) -> Analysis {
+        let mut stop_words: Vec<&str> = self.stop_words.iter().map(String::as_str).collect();
+        stop_words.sort_unstable();
+        Analysis { language: self.language, stop_words: fnv1a(stop_words.join("\n").as_bytes()) }
+    }
+
+    /// Reads the index file, or `None` if there isn't one yet, or it is in an older format or
+    /// damaged, and needs building.
+    fn load_index() -> Result<Option<Stored>, KprError> {
         let filepath = full_path(INDEX_FILENAME);
 
-        let file = OpenOptions::new()
-            .read(true)
-            .open(filepath)
-            .expect("Could not open index file");
+        let bytes = match std::fs::read(filepath) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Self::parse_index(&bytes)
+    }
 
-        if file.metadata().expect("Could not get metadata").len() == 0 {
-            return HashMap::new()
+    fn parse_index(bytes: &[u8]) -> Result<Option<Stored>, KprError> {
+        if bytes.is_empty() {
+            return Ok(None)
         };
 
-        let reader = BufReader::new(file);
-        bincode::deserialize_from(reader).expect("Could not deserialize index file")
+        let (version, postings) = Self::split_header(bytes);
+        if version > INDEX_VERSION {
+            return Err(KprError::new(&format!(
+                "{INDEX_FILENAME} is in format {version}, but this kpr only understands up to format {INDEX_VERSION}. Please upgrade kpr."
+            )));
+        }
+
+        // Before format 3, postings were 16 bit line numbers, which wrapped after 65,535 lines,
+        // before format 4 there was no fingerprint to tell whether the index was stale,
+        // before format 5 there were no word positions, before format 6 the fingerprint didn't say
+        // whether the store was in date order, before format 7 there were no note lengths,
+        // before format 8 there were no unstemmed words for wildcards to match,
+        // before format 9 nothing said which language and stop words it was built with,
+        // before format 10 words were only split at spaces,
+        // and before format 11 the fingerprint only hashed the end of the store.
+        if version < INDEX_VERSION {
+            return Ok(None);
+        }
+
+        // A half-written or otherwise damaged index is rebuilt rather than trusted.
+        Ok(bincode::deserialize(postings).ok())
     }
 
-    fn load_stopwords() -> HashSet<String> {
-        let filepath = full_path(STOPS_FILENAME);
-        let file = open_read(filepath).expect("Could not open stopwords file");
-        let reader = BufReader::new(file);
-        let mut stopwords = HashSet::new();
-    
-        for line in reader.lines() {
-            let line = line.expect("Could not parse line");
-            let clean_word = Self::clean(&line);
-            stopwords.insert(clean_word);
+    /// Splits an index file into its format version and the serialized postings.
+    fn split_header(bytes: &[u8]) -> (u32, &[u8]) {
+        match bytes.strip_prefix(INDEX_MAGIC) {
+            Some(rest) if rest.len() >= 4 => {
+                let (version, postings) = rest.split_at(4);
+                let version = u32::from_le_bytes(version.try_into().expect("Version is 4 bytes"));
+                (version, postings)
+            },
+            // Version 1 index files are the postings with no header.
+            _ => (1, bytes),
         }
-    
-        stopwords
     }
 
-    pub fn search(&self, query: &[String]) -> Vec<u16> {
+    /// Loads the notebook's stopwords, falling back to those in the data directory. Without
+    /// either, the built-in stop words for `language` are used.
+    fn load_stopwords(language: Language) -> HashSet<String> {
+        let candidates = [full_path(STOPS_FILENAME), notebooks::current().root.join(STOPS_FILENAME)];
+        let words = match candidates.into_iter().find_map(|filepath| std::fs::read(filepath).ok()) {
+            Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            None => language.stop_words().to_string(),
+        };
+
+        words
+            .lines()
+            .map(Self::clean)
+            .filter(|word| !word.is_empty())
+            .collect()
+    }
+
+    /// The lines matching `query` with their relevance, most relevant first, then newest first,
+    /// and the stems its words matched. Words match stems a few typos away if `fuzzy`, or if
+    /// nothing matches exactly.
+    /// `notes` are checked against any `field:value` terms, which the index knows nothing of.
+    pub fn scores(&self, query: &Query, fuzzy: bool, notes: &[(LineNumber, Record)]) -> (Vec<(LineNumber, f64)>, HashSet<String>) {
+        let query = query.split_words(&search_words);
+        let mut lookup = self.expand(&query, fuzzy, notes);
+        let mut scores = self.score_lines(&query, &lookup);
+        if scores.is_empty() && !fuzzy {
+            lookup = self.expand(&query, true, notes);
+            scores = self.score_lines(&query, &lookup);
+        }
+        (scores, lookup.stems)
+    }
+
+    /// Finds the postings for each word of `query`, expanding words with wildcards, and the
+    /// notes matching each of its fields.
+    fn expand(&self, query: &Query, fuzzy: bool, notes: &[(LineNumber, Record)]) -> Lookup {
+        let vocabulary = fuzzy.then(|| self.fuzzy_vocabulary());
+        let positive: HashSet<String> = query.terms().into_iter().collect();
 
-        let mut occurrences = Vec::new();
+        let mut lookup = Lookup::default();
+        for field in query.fields() {
+            let lines = notes
+                .iter()
+                .filter(|(_, record)| field.matches(record))
+                .map(|(line_number, _)| *line_number)
+                .collect();
+            lookup.fields.push((field.clone(), lines));
+        }
 
-        for word in query {
-            if self.is_stop(word) {
+        for word in query.words() {
+            if lookup.postings.contains_key(&word) || self.is_ignored(&word) {
                 continue;
             }
 
-            let stem = self.clean_and_stem(word);
-            let index_hits = self.lookup_word(&stem);
-            occurrences.extend(index_hits);
+            // Wildcards already match many stems, so they aren't also matched fuzzily.
+            let stem = self.clean_and_stem(&word);
+            let stems: Vec<String> = match &vocabulary {
+                _ if is_pattern(&word) => self.expand_pattern(&word),
+                Some(vocabulary) => vocabulary
+                    .find(&stem, max_typos(&stem))
+                    .into_iter()
+                    .map(|(_, stem)| stem.to_string())
+                    .collect(),
+                None => vec![stem],
+            };
+
+            let postings = merge_postings(stems.iter().filter_map(|stem| self.index.get(stem)));
+            if positive.contains(&word) {
+                lookup.stems.extend(stems);
+            }
+            lookup.postings.insert(word, postings);
         }
+        lookup
+    }
 
-        let mut counts: HashMap<u16, u16> = HashMap::new();
-        for line_number in occurrences {
-            *counts.entry(line_number).or_insert(0) += 1;
+    /// The stems of the words matching a word with wildcards, found by scanning those which start
+    /// with the part before the first wildcard. Words are matched as they were written, not by
+    /// their stems, so `deployme*` finds "deployment" though its stem is "deploy".
+    fn expand_pattern(&self, word: &str) -> Vec<String> {
+        let pattern = word.trim_matches(|c: char| !c.is_alphanumeric() && c != WILDCARD).to_lowercase();
+        let prefix = pattern.split(WILDCARD).next().unwrap_or_default();
+
+        let stems: BTreeSet<&String> = self.words
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(word, _)| word.starts_with(prefix))
+            .filter(|(word, _)| glob_matches(&pattern, word))
+            .map(|(_, word)| &word.stem)
+            .collect();
+        stems.into_iter().cloned().collect()
+    }
+
+    /// Every stem in the index, as a BK-tree. It is built once and kept until the stems change,
+    /// so a search which falls back to fuzzy matching and then makes suggestions builds it once.
+    fn fuzzy_vocabulary(&self) -> &BkTree {
+        self.fuzzy_vocabulary.get_or_init(|| {
+            BkTree::new(self.index.keys().filter(|key| !key.starts_with(TAG_PREFIX)).map(String::as_str))
+        })
+    }
+
+    /// Stems close to the query's words which aren't in any note, closest first.
+    pub fn suggestions(&self, query: &Query) -> Vec<String> {
+        let vocabulary = self.fuzzy_vocabulary();
+        let mut suggestions: Vec<String> = Vec::new();
+
+        for word in query.split_words(&search_words).terms() {
+            if self.is_ignored(&word) || is_pattern(&word) {
+                continue;
+            }
+            let stem = self.clean_and_stem(&word);
+            if self.index.contains_key(&stem) {
+                continue;
+            }
+            for (_, similar) in vocabulary.find(&stem, max_typos(&stem) + 1).into_iter().take(3) {
+                if !suggestions.iter().any(|suggestion| suggestion == similar) {
+                    suggestions.push(similar.to_string());
+                }
+            }
         }
+        suggestions
+    }
 
-        let mut counts: Vec<(u16, u16)> = counts.into_iter().collect();
-        
-        // reverse sort
-        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1));
-        counts.into_iter().map(|(line_number, _)| line_number).collect()
-        
+    /// The word each stem most often comes from in `records`, so that suggestions are real words.
+    pub fn surface_words<'a>(&self, stems: &[String], records: impl Iterator<Item=&'a Record>) -> Vec<String> {
+        let wanted: HashSet<&String> = stems.iter().collect();
+        let mut counts: HashMap<String, HashMap<String, usize>> = HashMap::new();
+
+        for record in records {
+            for token in tokenize(&record.message) {
+                let clean = Self::clean(token.text);
+                let stem = self.stem(&clean);
+                if wanted.contains(&stem) {
+                    *counts.entry(stem).or_default().entry(clean).or_default() += 1;
+                }
+            }
+        }
+
+        stems
+            .iter()
+            .filter_map(|stem| {
+                let words = counts.get(stem)?;
+                let (word, _) = words.iter().max_by_key(|&(word, count)| (count, std::cmp::Reverse(word)))?;
+                Some(word.clone())
+            })
+            .collect()
+    }
+
+    /// The lines matching `query` and the stems to highlight, without ranking them or trying
+    /// fuzzy matches when nothing matches, so that notes can be filtered as fast as someone types.
+    /// There are no lines when the query doesn't rule any notes out, like one made of stop words.
+    pub fn filter(&self, query: &Query, notes: &[(LineNumber, Record)]) -> (Option<HashSet<LineNumber>>, HashSet<String>) {
+        let query = query.split_words(&search_words);
+        let lookup = self.expand(&query, false, notes);
+        (self.lines_of(self.matches(&query, &lookup)), lookup.stems)
+    }
+
+    fn matching_lines(&self, query: &Query, lookup: &Lookup) -> HashSet<LineNumber> {
+        // A search made only of stop words has nothing to look for.
+        self.lines_of(self.matches(query, lookup)).unwrap_or_default()
+    }
+
+    /// The lines in `matches`, or none if it matches every note.
+    fn lines_of(&self, matches: Matches) -> Option<HashSet<LineNumber>> {
+        match matches {
+            Matches::Only(lines) => Some(lines),
+            Matches::AllBut(excluded) => Some(self.all_lines().difference(&excluded).copied().collect()),
+            Matches::All => None,
+        }
+    }
+
+    fn score_lines(&self, query: &Query, lookup: &Lookup) -> Vec<(LineNumber, f64)> {
+        let lines = self.matching_lines(query, lookup);
+
+        // Stop words are left out, but still count towards the gaps between the other words.
+        let words = query.terms();
+        let terms: Vec<(u32, &[Posting])> = words
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| !self.is_ignored(word))
+            .map(|(offset, word)| (offset as u32, lookup.postings(word)))
+            .collect();
+
+        let average_length = self.average_length();
+        let mut scores: Vec<(LineNumber, f64)> = lines
+            .into_iter()
+            .map(|line_number| (line_number, self.score(line_number, &terms, average_length)))
+            .collect();
+        sort_by_score(&mut scores, |&score| score);
+        scores
+    }
+
+    /// How many words notes have on average. It takes a pass over every note, so is worked out
+    /// once per search rather than for each note scored.
+    fn average_length(&self) -> f64 {
+        let total: f64 = self.lengths.values().map(|&length| length as f64).sum();
+        total / (self.lengths.len() as f64).max(1.0)
+    }
+
+    /// The BM25 relevance of a line to the words of a query, boosted when the words
+    /// appear side by side or in the same order as in the query.
+    fn score(&self, line_number: LineNumber, terms: &[(u32, &[Posting])], average_length: f64) -> f64 {
+        const K1: f64 = 1.2;
+        const B: f64 = 0.75;
+
+        let note_count = self.lengths.len() as f64;
+        let length = self.lengths.get(&line_number).copied().unwrap_or_default() as f64;
+
+        let bm25: f64 = terms
+            .iter()
+            .map(|&(_, postings)| {
+                let frequency = positions_on(postings, line_number).len() as f64;
+                let document_frequency = postings.len() as f64;
+                let idf = ((note_count - document_frequency + 0.5) / (document_frequency + 0.5) + 1.0).ln();
+                idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length / average_length.max(1.0)))
+            })
+            .sum();
+
+        let (adjacent, in_order) = self.proximity(line_number, terms);
+        bm25 * (1.0 + 0.5 * adjacent as f64 + 0.25 * in_order as f64)
+    }
+
+    /// How many neighbouring pairs of a query's words appear side by side on `line_number`,
+    /// and how many appear in the same order.
+    fn proximity(&self, line_number: LineNumber, terms: &[(u32, &[Posting])]) -> (usize, usize) {
+        let found: Vec<(u32, &[u32])> = terms
+            .iter()
+            .map(|&(offset, postings)| (offset, positions_on(postings, line_number)))
+            .filter(|(_, positions)| !positions.is_empty())
+            .collect();
+
+        let mut adjacent = 0;
+        let mut in_order = 0;
+        for pair in found.windows(2) {
+            let [(offset_a, positions_a), (offset_b, positions_b)] = pair else { continue };
+            let gap = offset_b - offset_a;
+            if positions_a.iter().any(|a| positions_b.contains(&(a + gap))) {
+                adjacent += 1;
+            }
+            if positions_a.iter().any(|a| positions_b.iter().any(|b| b > a)) {
+                in_order += 1;
+            }
+        }
+        (adjacent, in_order)
+    }
+
+    fn matches(&self, query: &Query, lookup: &Lookup) -> Matches {
+        match query {
+            Query::Term(word) => self.matches_word(word, lookup),
+            Query::Phrase(words) => match self.phrase_spans(words, lookup) {
+                Some(spans) => Matches::Only(spans.into_keys().collect()),
+                None => Matches::All,
+            },
+            Query::Near(left, right, distance) => match (self.phrase_spans(left, lookup), self.phrase_spans(right, lookup)) {
+                (Some(left), Some(right)) => Matches::Only(
+                    left
+                        .into_iter()
+                        .filter(|(line_number, left)| right.get(line_number).is_some_and(|right| is_near(left, right, *distance)))
+                        .map(|(line_number, _)| line_number)
+                        .collect()
+                ),
+                (Some(spans), None) | (None, Some(spans)) => Matches::Only(spans.into_keys().collect()),
+                (None, None) => Matches::All,
+            },
+            Query::And(queries) => queries
+                .iter()
+                .map(|query| self.matches(query, lookup))
+                .fold(Matches::All, Matches::and),
+            Query::Or(queries) => queries
+                .iter()
+                .map(|query| self.matches(query, lookup))
+                .reduce(Matches::or)
+                .unwrap_or(Matches::All),
+            Query::Not(query) => self.matches(query, lookup).not(),
+            Query::Field(field) => Matches::Only(lookup.field_lines(field)),
+        }
+    }
+
+    /// Stop words, and words which are only punctuation, match every note.
+    fn matches_word(&self, word: &str, lookup: &Lookup) -> Matches {
+        if self.is_ignored(word) {
+            return Matches::All;
+        }
+        Matches::Only(lines_of(lookup.postings(word)).into_iter().collect())
+    }
+
+    /// Where `words` appear together and in order, or `None` if they are all stop words.
+    fn phrase_spans(&self, words: &[String], lookup: &Lookup) -> Option<Spans> {
+        let terms: Vec<(u32, &[Posting])> = words
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| !self.is_ignored(word))
+            .map(|(offset, word)| (offset as u32, lookup.postings(word)))
+            .collect();
+
+        let &(first_offset, first_postings) = terms.first()?;
+        let length = words.len() as u32;
+
+        let mut spans = Spans::new();
+        for posting in first_postings {
+            for &position in &posting.positions {
+                let Some(start) = position.checked_sub(first_offset) else { continue };
+                let rest_follow = terms[1..]
+                    .iter()
+                    .all(|&(offset, postings)| positions_on(postings, posting.line).contains(&(start + offset)));
+                if rest_follow {
+                    spans.entry(posting.line).or_default().push((start, start + length - 1));
+                }
+            }
+        }
+        Some(spans)
+    }
+
+    /// Every line which has been indexed.
+    fn all_lines(&self) -> HashSet<LineNumber> {
+        self.lengths.keys().copied().collect()
+    }
+
+    /// The notes most like the one on `line_number`, most similar first, with the stems they
+    /// share with it. Notes are compared by the cosine similarity of their stems, each weighted
+    /// by how often it appears in the note and how rare it is across notes (TF-IDF), so sharing
+    /// an unusual word counts for more than sharing a common one.
+    pub fn related(&self, line_number: LineNumber) -> (Vec<(LineNumber, f64)>, HashSet<String>) {
+        let note_count = self.lengths.len() as f64;
+        let mut norms: HashMap<LineNumber, f64> = HashMap::new();
+        // The note's stems, with their weight in it, their rarity and where else they appear.
+        let mut shared: Vec<(&str, f64, f64, &[Posting])> = Vec::new();
+
+        for (stem, postings) in self.index.iter().filter(|(key, _)| !key.starts_with(TAG_PREFIX)) {
+            let idf = (1.0 + note_count / postings.len() as f64).ln();
+            for posting in postings {
+                *norms.entry(posting.line).or_default() += (posting.positions.len() as f64 * idf).powi(2);
+            }
+            let frequency = positions_on(postings, line_number).len() as f64;
+            if frequency > 0.0 {
+                shared.push((stem, frequency * idf, idf, postings));
+            }
+        }
+
+        let mut products: HashMap<LineNumber, f64> = HashMap::new();
+        for &(_, weight, idf, postings) in &shared {
+            for posting in postings.iter().filter(|posting| posting.line != line_number) {
+                *products.entry(posting.line).or_default() += weight * posting.positions.len() as f64 * idf;
+            }
+        }
+
+        let norm = norms.get(&line_number).copied().unwrap_or_default().sqrt();
+        let mut scores: Vec<(LineNumber, f64)> = products
+            .into_iter()
+            .map(|(other, product)| (other, product / (norm * norms[&other].sqrt())))
+            .collect();
+        sort_by_score(&mut scores, |&score| score);
+
+        let stems = shared.into_iter().map(|(stem, _, _, _)| stem.to_string()).collect();
+        (scores, stems)
     }
 }
     
 
 #[cfg(test)]
 mod tests {
+    use colored::Colorize;
+
     use super::*;
-    const INDEX_FILE: &'static str = "
+    const INDEX_FILE: &str = "
     1680917693908: message two
     1680917698382: message 3
     1680917701962: message three
@@ -216,6 +1110,21 @@ mod tests {
     1680917733553: one more time
 ";
 
+    impl Index {
+        fn lookup_word(&self, word: &str) -> Vec<LineNumber> {
+            let stem = self.clean_and_stem(word);
+            lines_of(self.index.get(&stem).map_or(&[], Vec::as_slice))
+        }
+    }
+
+    fn search(index: &Index, query: &Query) -> Vec<LineNumber> {
+        index.scores(query, false, &[]).0.into_iter().map(|(line_number, _)| line_number).collect()
+    }
+
+    fn parse(text: &str) -> Query {
+        Query::parse(text).expect("Could not parse query").expect("Query is empty")
+    }
+
     #[test]
     fn build_index_works() {
         let lines = INDEX_FILE.lines().map(|l| l.to_string());
@@ -234,27 +1143,409 @@ mod tests {
         let lines: Vec<String> = INDEX_FILE.lines().map(|l| l.to_string()).collect();
 
         let index = Index::from_lines(lines.clone());
-        let query = vec!["message".to_string(), "three".to_string()];
+        let query = parse("message three");
         let mut stop_words = HashSet::new();
         stop_words.insert("the".to_string());
 
-        let results = index.search(&query);
-        assert!(results.len() > 0);
+        let results = search(&index, &query);
+        assert!(!results.is_empty());
         assert!(results.len() <= lines.len());
         println!("{:?}", &results);
         
-        let query = vec!["nothing".to_string()];
-        let results = index.search(&query);
+        let query = parse("nothing");
+        let results = search(&index, &query);
         println!("{:?}", &results);
         println!("{:?}", &index.index);
 
         assert!(results.len() == 1);
         
-        let query = vec!["three".to_string()];
-        let results = index.search(&query);
+        let query = parse("three");
+        let results = search(&index, &query);
         assert!(results.len() == 2);
     }
 
+    #[test]
+    fn test_boolean_search() {
+        let index = Index::from_lines(INDEX_FILE.lines().map(|l| l.to_string()));
+
+        assert_eq!(search(&index, &parse("message three")), vec![3]);
+        assert_eq!(search(&index, &parse("message -three -two")), vec![4, 8, 2, 9]);
+        assert_eq!(search(&index, &parse("three OR whatever")), vec![6, 5, 3]);
+        assert_eq!(search(&index, &parse("(two | three) & message")), vec![1, 3]);
+        assert_eq!(search(&index, &parse("-message -three")).len(), 3);
+    }
+
+    fn index_of(messages: &[&str]) -> Index {
+        let mut index = Index::from_lines(Vec::<String>::new());
+        for (line_number, message) in messages.iter().enumerate() {
+            index.add_line(line_number as LineNumber, &Record::create(message.to_string()));
+        }
+        index
+    }
+
+    #[test]
+    fn test_phrase_search() {
+        let index = index_of(&[
+            "the disk is full on db-1",
+            "full backup of the disk",
+            "disk full again",
+        ]);
+
+        assert_eq!(search(&index, &parse("\"disk full\"")), vec![2]);
+        assert_eq!(search(&index, &parse("\"disk is full\"")), vec![0]);
+        assert_eq!(search(&index, &parse("\"disk\" -\"disk full\"")), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_near_search() {
+        let index = index_of(&[
+            "disk on db-1 is now completely full",
+            "full backup of the disk",
+        ]);
+
+        assert_eq!(search(&index, &parse("disk NEAR/2 full")), Vec::<LineNumber>::new());
+        assert_eq!(search(&index, &parse("disk NEAR/4 full")), vec![1]);
+        assert_eq!(search(&index, &parse("disk NEAR/7 full")), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_words_in_order_rank_higher() {
+        let index = index_of(&[
+            "disk full on db-1",
+            "full backup of the disk",
+            "the disk of db-2 is full",
+        ]);
+
+        assert_eq!(search(&index, &parse("disk full")), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn test_short_precise_notes_rank_higher() {
+        let index = index_of(&[
+            "deploy api",
+            "long rambling note about the deploy of the api and the web and many other things besides",
+            "deploy web",
+        ]);
+
+        assert_eq!(search(&index, &parse("deploy api")), vec![0, 1]);
+        assert_eq!(search(&index, &parse("deploy")), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_recency_boost() {
+        assert!(recency_boost(Duration::zero()) > recency_boost(Duration::days(30)));
+        assert!((recency_boost(Duration::days(30)) - 1.5).abs() < 1e-9);
+        assert!(recency_boost(Duration::days(3650)) >= 1.0);
+    }
+
+    #[test]
+    fn test_fuzzy_search_is_used_when_nothing_matches() {
+        let index = index_of(&["restarted the kubernetes cluster", "kubectl drain node-3", "deploy api"]);
+
+        assert_eq!(search(&index, &parse("kuberntes")), vec![0]);
+        assert_eq!(search(&index, &parse("kubernetes")), vec![0]);
+        assert_eq!(search(&index, &parse("deplyo api")), vec![2]);
+
+        let (scores, stems) = index.scores(&parse("clustr"), true, &[]);
+        assert_eq!(scores.len(), 1);
+        assert!(stems.contains("cluster"));
+
+        assert_eq!(index.filter(&parse("kuberntes"), &[]).0, Some(HashSet::new()));
+        assert_eq!(index.filter(&parse("kubernetes"), &[]).0, Some(HashSet::from([0])));
+        assert_eq!(index.filter(&parse("the"), &[]).0, None);
+    }
+
+    #[test]
+    fn test_fuzzy_vocabulary_is_kept_until_the_stems_change() {
+        let mut index = index_of(&["restarted the kubernetes cluster"]);
+        assert!(std::ptr::eq(index.fuzzy_vocabulary(), index.fuzzy_vocabulary()));
+        let fuzzy_lines = |index: &Index| index.scores(&parse("failovr"), true, &[]).0.len();
+        assert_eq!(fuzzy_lines(&index), 0);
+
+        let record = Record::create("postgres failover".to_string());
+        index.add_line(1, &record);
+        assert_eq!(fuzzy_lines(&index), 1);
+
+        index.remove_line(1, &record);
+        assert_eq!(fuzzy_lines(&index), 0);
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let index = index_of(&["restarted the kubernetes cluster", "kubectl drain node-3"]);
+
+        assert!(search(&index, &parse("kubarnatis")).is_empty());
+        let suggestions = index.suggestions(&parse("kubarnatis"));
+        assert_eq!(suggestions, vec!["kubernet"]);
+
+        let records = [Record::create("restarted the kubernetes cluster".to_string())];
+        assert_eq!(index.surface_words(&suggestions, records.iter()), vec!["kubernetes"]);
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("inc-20*", "inc-2041"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*back*", "rollback"));
+        assert!(glob_matches("d*k", "disk"));
+        assert!(!glob_matches("d*k", "disks"));
+        assert!(!glob_matches("a*a", "a"));
+    }
+
+    #[test]
+    fn test_wildcard_search() {
+        let index = index_of(&[
+            "paged for INC-2041",
+            "INC-1999 was a false alarm",
+            "rollback of the api",
+            "the backup finished",
+        ]);
+
+        assert_eq!(search(&index, &parse("INC-20*")), vec![0]);
+        assert_eq!(search(&index, &parse("inc-*")), vec![0, 1]);
+        assert_eq!(search(&index, &parse("*back*")), vec![3, 2]);
+        assert_eq!(search(&index, &parse("\"paged for inc-*\"")), vec![0]);
+        assert_eq!(search(&index, &parse("*")).len(), 4);
+        assert!(search(&index, &parse("zzz*")).is_empty());
+
+        let (_, stems) = index.scores(&parse("INC-20* -rollback"), false, &[]);
+        assert_eq!(stems, HashSet::from(["inc".to_string(), "2041".to_string()]));
+        assert!(index.suggestions(&parse("zzz*")).is_empty());
+    }
+
+    #[test]
+    fn test_wildcards_match_words_past_the_end_of_their_stems() {
+        let index = index_of(&["the deployment failed", "restart kubernetes", "deploy api"]);
+
+        // The expansion is to stems, so a pattern finds what the whole word would.
+        assert_eq!(search(&index, &parse("deployme*")), search(&index, &parse("deployment")));
+        assert_eq!(search(&index, &parse("deploym*")), vec![2, 0]);
+        assert_eq!(search(&index, &parse("deploy*")), vec![2, 0]);
+        assert_eq!(search(&index, &parse("kubernete*")), vec![1]);
+        assert_eq!(search(&index, &parse("kubernetes*")), vec![1]);
+        assert!(search(&index, &parse("kubernetesx*")).is_empty());
+
+        let (_, stems) = index.scores(&parse("deployme*"), false, &[]);
+        assert_eq!(stems, HashSet::from(["deploy".to_string()]));
+    }
+
+    #[test]
+    fn test_words_are_split_at_punctuation_and_between_characters() {
+        let index = index_of(&["restarted db-1 today", "db 2 and 1 more", "東京都に行く", "京都の寺"]);
+
+        assert_eq!(search(&index, &parse("db-1")), vec![0]);
+        assert_eq!(search(&index, &parse("東京都")), vec![2]);
+        let mut kyoto = search(&index, &parse("京都"));
+        kyoto.sort_unstable();
+        assert_eq!(kyoto, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_single_characters_match_inside_runs_without_spaces() {
+        let index = index_of(&["東京都に行く", "京都の寺", "猫が好き", "猫 and 犬"]);
+
+        let mut kyo = search(&index, &parse("京"));
+        kyo.sort_unstable();
+        assert_eq!(kyo, vec![0, 1]);
+        let mut cat = search(&index, &parse("猫"));
+        cat.sort_unstable();
+        assert_eq!(cat, vec![2, 3]);
+        assert_eq!(search(&index, &parse("き")), vec![2]);
+    }
+
+    #[test]
+    fn test_highlighting_colours_the_matched_tokens() {
+        let highlight = make_match_formatter(HashSet::from(["deploy".to_string()]), None);
+        let message = "Deploy, then (deploying) db-1".to_string();
+        assert_eq!(highlight(&message), format!("{}, then ({}) db-1", "Deploy".green(), "deploying".green()));
+    }
+
+    #[test]
+    fn test_boolean_search_ignores_stop_words() {
+        let index = Index::from_lines(INDEX_FILE.lines().map(|l| l.to_string()));
+
+        assert_eq!(search(&index, &parse("the three")), search(&index, &parse("three")));
+        assert!(search(&index, &parse("the")).is_empty());
+    }
+
+    #[test]
+    fn test_fields_combine_with_words() {
+        let lines: Vec<String> = ["deploy api #oncall", "deploy web", "restart api #oncall", &"deploy ".repeat(50)]
+            .iter()
+            .map(|message| Entry::Note(Record::create(message.to_string())).to_store())
+            .collect();
+        let index = Index::from_lines(lines.clone());
+        let notes = store::replay(lines);
+        let search = |text: &str| -> Vec<LineNumber> {
+            let mut lines: Vec<LineNumber> = index.scores(&parse(text), false, &notes).0.into_iter().map(|(line_number, _)| line_number).collect();
+            lines.sort();
+            lines
+        };
+
+        assert_eq!(search("deploy tag:oncall"), vec![0]);
+        assert_eq!(search("deploy -tag:oncall"), vec![1, 3]);
+        assert_eq!(search("tag:oncall | len:>100"), vec![0, 2, 3]);
+        assert_eq!(search("api (tag:nope OR restart)"), vec![2]);
+        assert_eq!(search(&format!("id:{}", notes[1].1.id)), vec![1]);
+    }
+
+    #[test]
+    fn test_related_notes_favour_rare_shared_words() {
+        let index = index_of(&[
+            "postgres failover on db-1 during deploy",
+            "deploy of the api went fine",
+            "postgres failover again on db-1",
+            "deploy web",
+            "lunch",
+        ]);
+        let (scores, stems) = index.related(0);
+        let lines: Vec<LineNumber> = scores.iter().map(|&(line_number, _)| line_number).collect();
+        assert_eq!(lines, vec![2, 3, 1]);
+        assert!(scores.iter().all(|&(_, score)| score > 0.0 && score <= 1.0 + 1e-9));
+        assert!(stems.contains("failov") && stems.contains("deploy"));
+
+        let (scores, _) = index.related(4);
+        assert!(scores.is_empty());
+    }
+
+    #[test]
+    fn test_remove_line() {
+        let mut index = Index::from_lines(Vec::<String>::new());
+        let first = Record::create("Test message".to_string());
+        let second = Record::create("Another test".to_string());
+        index.add_line(0, &first);
+        index.add_line(1, &second);
+
+        index.remove_line(0, &first);
+
+        assert_eq!(index.lookup_word("test"), vec![1]);
+        assert!(index.lookup_word("message").is_empty());
+        assert!(!index.index.contains_key("messag"));
+        assert!(!index.words.contains_key("message"));
+        assert_eq!(index.words["test"].notes, 1);
+    }
+
+    #[test]
+    fn test_tags_are_indexed_separately() {
+        let mut index = Index::from_lines(Vec::<String>::new());
+        index.add_line(0, &Record::create("#deploy of api #oncall".to_string()));
+        index.add_line(1, &Record::create("deploy of web #deploy".to_string()));
+        index.add_line(2, &Record::create("paged #oncall".to_string()));
+
+        assert_eq!(index.lookup_tags(&["deploy".to_string()]), vec![0, 1]);
+        assert_eq!(index.lookup_tags(&["deploy".to_string(), "oncall".to_string()]), vec![0]);
+        assert!(index.lookup_tags(&["api".to_string()]).is_empty());
+        assert_eq!(index.tag_counts(), vec![("deploy".to_string(), 2), ("oncall".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_line_numbers_beyond_u16() {
+        let mut index = Index::from_lines(Vec::<String>::new());
+        index.add_line(70_000, &Record::create("Test message".to_string()));
+        index.add_line(4, &Record::create("Another test".to_string()));
+
+        assert_eq!(index.lookup_word("message"), vec![70_000]);
+        assert_eq!(search(&index, &parse("test | message"))[0], 70_000);
+    }
+
+    #[test]
+    fn test_index_file_header() {
+        let mut bytes = INDEX_MAGIC.to_vec();
+        bytes.extend(INDEX_VERSION.to_le_bytes());
+        bytes.extend([1, 2, 3]);
+        assert_eq!(Index::split_header(&bytes), (INDEX_VERSION, &[1u8, 2, 3][..]));
+
+        let legacy = bincode::serialize(&HashMap::<String, Vec<LineNumber>>::new()).expect("Could not serialize");
+        assert_eq!(Index::split_header(&legacy), (1, &legacy[..]));
+    }
+
+    #[test]
+    fn test_damaged_index_needs_rebuilding() {
+        let mut bytes = INDEX_MAGIC.to_vec();
+        bytes.extend(INDEX_VERSION.to_le_bytes());
+        bytes.extend([255, 255, 255]);
+        assert!(Index::parse_index(&bytes).expect("Could not parse index").is_none());
+
+        bytes.truncate(6);
+        assert!(Index::parse_index(&bytes).expect("Could not parse index").is_none());
+
+        let mut newer = INDEX_MAGIC.to_vec();
+        newer.extend((INDEX_VERSION + 1).to_le_bytes());
+        assert!(Index::parse_index(&newer).is_err());
+    }
+
+    fn store_bytes(entries: &[Entry]) -> Vec<u8> {
+        let lines: String = entries.iter().map(|entry| format!("{}\n", entry.to_store())).collect();
+        format!("{}\n{lines}", store::header()).into_bytes()
+    }
+
+    #[test]
+    fn test_sync_indexes_appended_notes() {
+        let first = Record::create("deploy the api".to_string());
+        let second = Record::create("rollback the api".to_string());
+        let mut index = Index::from_store_bytes(&store_bytes(&[Entry::Note(first.clone())]));
+
+        assert!(!index.sync(&store_bytes(&[Entry::Note(first.clone())])));
+        assert!(index.sync(&store_bytes(&[Entry::Note(first), Entry::Note(second)])));
+
+        assert_eq!(index.lookup_word("api"), vec![1, 2]);
+        assert_eq!(index.lookup_word("rollback"), vec![2]);
+    }
+
+    #[test]
+    fn test_sync_applies_appended_edits_and_deletions() {
+        let first = Record::create("deploy the api".to_string());
+        let second = Record::create("rollback the api".to_string());
+        let notes = [Entry::Note(first.clone()), Entry::Note(second.clone())];
+        let mut index = Index::from_store_bytes(&store_bytes(&notes));
+
+        let edit = Entry::Edit(Record::new(first.id.clone(), first.timestamp, "deploy the web".to_string()));
+        let delete = Entry::Delete { id: second.id.clone(), timestamp: second.timestamp };
+        let [note_a, note_b] = notes;
+        let bytes = store_bytes(&[note_a, note_b, edit, delete]);
+        assert!(index.sync(&bytes));
+
+        assert!(index.lookup_word("api").is_empty());
+        assert_eq!(index.lookup_word("web"), vec![1]);
+        assert_eq!(index.index, Index::from_store_bytes(&bytes).index);
+        assert_eq!(index.words, Index::from_store_bytes(&bytes).words);
+    }
+
+    #[test]
+    fn test_sync_rebuilds_when_earlier_lines_change() {
+        let first = Record::create("deploy the api".to_string());
+        let mut index = Index::from_store_bytes(&store_bytes(&[Entry::Note(first.clone())]));
+
+        let replaced = Record::new(first.id, first.timestamp, "restore the backup".to_string());
+        assert!(index.sync(&store_bytes(&[Entry::Note(replaced)])));
+
+        assert!(index.lookup_word("api").is_empty());
+        assert_eq!(index.lookup_word("backup"), vec![1]);
+    }
+
+    #[test]
+    fn test_every_language_has_stop_words() {
+        for language in Language::value_variants() {
+            let stop_words: Vec<&str> = language.stop_words().lines().collect();
+            assert!(stop_words.len() > 20, "{language:?} has too few stop words");
+            assert!(stop_words.iter().all(|word| !Index::clean(word).is_empty()), "{language:?} has an empty stop word");
+        }
+    }
+
+    #[test]
+    fn test_analysis_changes_with_language_and_stop_words() {
+        let mut index = Index::from_lines(Vec::<String>::new());
+        let analysis = index.analysis();
+
+        index.stop_words.insert("kubernetes".to_string());
+        assert_ne!(index.analysis(), analysis);
+
+        index.stop_words.remove("kubernetes");
+        assert_eq!(index.analysis(), analysis);
+        index.language = Language::German;
+        assert_ne!(index.analysis(), analysis);
+    }
+
     #[test]
     fn test_clean() {
         let cleaned_word = Index::clean("   Test!  ");
@@ -263,21 +1554,21 @@ mod tests {
 
     #[test]
     fn test_stem() {
-        let index = Index::load();
+        let index = index_of(&[]);
         let stemmed_word = index.stem("running");
         assert_eq!(stemmed_word, "run");
     }
 
     #[test]
     fn test_clean_and_stem() {
-        let index = Index::load();
+        let index = index_of(&[]);
         let cleaned_and_stemmed_word = index.clean_and_stem("   Running!  ");
         assert_eq!(cleaned_and_stemmed_word, "run");
     }
 
     #[test]
     fn test_stop_word_detection() {
-        let index = Index::load();
+        let index = index_of(&[]);
         assert!(index.is_stop("the"));
         assert!(!index.is_stop("test"));
     }
@@ -285,7 +1576,7 @@ mod tests {
     #[test]
     fn test_add_word() {
         let mut index = Index::from_lines(Vec::<String>::new());
-        index.add_word("test", 0);
+        index.add_word("test", 0, 0);
 
         assert_eq!(index.lookup_word("test"), vec![0]);
     }
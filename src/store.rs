@@ -1,19 +1,108 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Local};
 use rev_buf_reader::RevBufReader;
+use serde::{Deserialize, Serialize};
 
+use crate::errors::KprError;
+use crate::helpers::fnv1a;
 use crate::locks::LockGuard;
-use crate::records::Record;
+use crate::notebooks;
+use crate::records::{Entry, Record};
 
 pub const STORE_FILENAME: &str = "store.txt";
 
+/// The version of the store format this kpr reads and writes.
+/// Version 1 stores have no header, and their lines have no IDs and aren't escaped.
+pub const FORMAT_VERSION: u32 = 2;
+const HEADER_PREFIX: &str = "# kpr store format ";
+
+/// Notes are addressed by the line of the store they were first written on.
+pub type LineNumber = u32;
+
+/// A cheap summary of the store's contents, kept in the index so that changes made behind
+/// kpr's back (hand edits, restored backups, syncing) can be noticed.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Fingerprint {
+    /// The length of the store in bytes.
+    pub len: u64,
+    pub lines: LineNumber,
+    /// The hash of the whole store, so that edits anywhere in it are noticed.
+    pub hash: u64,
+    /// Whether every entry was written no earlier than the one before it, which a store kpr has
+    /// only appended to always is, but a merged or hand-edited one may not be.
+    pub in_date_order: bool,
+}
+
+impl Fingerprint {
+    pub fn of(bytes: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(bytes);
+        Fingerprint {
+            len: bytes.len() as u64,
+            lines: text.lines().count() as LineNumber,
+            hash: fnv1a(bytes),
+            in_date_order: in_date_order(text.lines()),
+        }
+    }
+
+    /// Whether this is the fingerprint of the store on disk, judged by its length and then
+    /// its hash, so that the store is only read when the lengths agree.
+    pub fn matches_store(&self) -> Result<bool, std::io::Error> {
+        let Some(mut file) = open_store() else { return Ok(self.len == 0) };
+        if file.metadata()?.len() != self.len {
+            return Ok(false);
+        }
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(fnv1a(&bytes) == self.hash)
+    }
+
+    /// Whether `bytes` starts with the store this is a fingerprint of, followed by whole lines.
+    pub fn is_prefix_of(&self, bytes: &[u8]) -> bool {
+        let len = self.len as usize;
+        if bytes.len() < len {
+            return false;
+        }
+
+        let prefix = &bytes[..len];
+        let ends_with_whole_line = prefix.is_empty() || prefix.ends_with(b"\n");
+        ends_with_whole_line && fnv1a(prefix) == self.hash
+    }
+}
+
+/// The fingerprint of an empty store.
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Fingerprint::of(&[])
+    }
+}
+
+/// Whether the timestamps which start the entries in `lines` never go backwards.
+fn in_date_order<'a>(lines: impl Iterator<Item=&'a str>) -> bool {
+    let timestamps = lines.filter_map(|line| {
+        let end = line.find([' ', ':'])?;
+        line[..end].parse::<i64>().ok()
+    });
+
+    let mut latest = i64::MIN;
+    for timestamp in timestamps {
+        if timestamp < latest {
+            return false;
+        }
+        latest = timestamp;
+    }
+    true
+}
+
+/// The path of a file in the selected notebook.
 pub fn full_path<S>(filename: S) -> PathBuf 
     where PathBuf: From<S> {
         let filename = PathBuf::from(filename);
-        let home = dirs::home_dir().expect("Could not find home directory");
-        home.join(".kpr").join(filename)
+        notebooks::current().dir().join(filename)
 }
 
 pub fn open_or_create<S>(filepath: S, append: bool) -> Result<File, std::io::Error> 
@@ -43,7 +132,7 @@ pub fn open_read<S>(filepath: S) -> Result<File, std::io::Error>
 fn load_lines_from(file: File, n: Option<usize>) -> Vec<String> {
     let lines_from_last = RevBufReader::new(file)
         .lines()
-        .filter_map(|line| line.ok());
+        .map_while(Result::ok);
 
     let lines: Vec<String> = match n {
         Some(n_lines) => lines_from_last.take(n_lines).collect(),
@@ -57,39 +146,380 @@ fn load_lines_from(file: File, n: Option<usize>) -> Vec<String> {
 }
 
 
-pub fn load_lines(n: Option<usize>) -> Vec<String> {
+/// Opens the store for reading, or returns `None` if nothing has been kept yet.
+fn open_store() -> Option<File> {
     let filepath = full_path(STORE_FILENAME);
-    let file = open_read(filepath).expect("Could not open store file");
-    load_lines_from(file, n)
+    match open_read(filepath) {
+        Ok(file) => Some(file),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => panic!("Could not open store file: {e}"),
+    }
 }
 
-pub fn load_records(n: Option<usize>) -> Vec<Record> {
-    load_lines(n)
-        .iter()
-        .filter_map(|line| Record::from_store(&line))
-        .collect()
+/// The whole store as it is on disk, or nothing if nothing has been kept yet.
+pub fn read_bytes() -> Result<Vec<u8>, std::io::Error> {
+    match std::fs::read(full_path(STORE_FILENAME)) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
 }
 
-pub fn write(record: &Record) -> Result<u16, std::io::Error> {
-    let filepath = full_path(STORE_FILENAME);
-    let file = open_or_create(filepath, true)?;
+/// Splits raw store contents into lines.
+pub fn lines_of(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes).lines().map(ToString::to_string).collect()
+}
+
+pub fn load_lines(n: Option<usize>) -> Vec<String> {
+    match open_store() {
+        Some(file) => load_lines_from(file, n),
+        None => Vec::new(),
+    }
+}
+
+/// Loads the `n` most recent notes for which `predicate` is true, oldest first,
+/// with any edits and deletions applied. Reading stops at the first note from before `since`,
+/// so it should only be given when the store is known to be in date order.
+pub fn load_records<P>(n: Option<usize>, since: Option<DateTime<Local>>, predicate: P) -> Vec<Record>
+    where P: Fn(&Record) -> bool {
+        let file = match open_store() {
+            Some(file) => file,
+            None => return Vec::new(),
+        };
+
+        // Reading newest first means an edit or tombstone is always seen before the note it refers to.
+        let mut deleted = HashSet::new();
+        let mut edits = HashMap::new();
+
+        let records = RevBufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| Entry::from_store(&line))
+            .take_while(|entry| match (entry, since) {
+                (Entry::Note(record), Some(since)) => record.timestamp >= since,
+                _ => true,
+            })
+            .filter_map(|entry| match entry {
+                Entry::Note(record) => {
+                    if deleted.contains(&record.id) { return None }
+                    let record = match edits.remove(&record.id) {
+                        Some(message) => Record::new(record.id, record.timestamp, message),
+                        None => record,
+                    };
+                    Some(record).filter(&predicate)
+                },
+                Entry::Edit(record) => {
+                    edits.entry(record.id).or_insert(record.message);
+                    None
+                },
+                Entry::Delete { id, .. } => {
+                    deleted.insert(id);
+                    None
+                },
+            });
+
+        let records: Vec<Record> = match n {
+            Some(n_records) => records.take(n_records).collect(),
+            None => records.collect(),
+        };
+
+        records
+            .into_iter()
+            .rev()
+            .collect()
+}
+
+/// Replays the entries in `lines`, returning every live note with the line number it was
+/// first written on and its most recent message.
+pub fn replay(lines: impl IntoIterator<Item=String>) -> Vec<(LineNumber, Record)> {
+    let mut notes: Vec<Option<(LineNumber, Record)>> = Vec::new();
+    let mut positions = HashMap::new();
+
+    for (line_number, line) in lines.into_iter().enumerate() {
+        let entry = match Entry::from_store(&line) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        match entry {
+            Entry::Note(record) => {
+                positions.insert(record.id.clone(), notes.len());
+                notes.push(Some((line_number as LineNumber, record)));
+            },
+            Entry::Edit(edit) => {
+                if let Some(Some((_, record))) = positions.get(&edit.id).map(|&i| &mut notes[i]) {
+                    *record = Record::new(edit.id, record.timestamp, edit.message);
+                }
+            },
+            Entry::Delete { id, .. } => {
+                if let Some(&i) = positions.get(&id) {
+                    notes[i] = None;
+                }
+            },
+        }
+    }
+
+    notes.into_iter().flatten().collect()
+}
+
+/// Finds the live note whose ID starts with `id_prefix`, along with its line number.
+pub fn find(id_prefix: &str) -> Result<(LineNumber, Record), KprError> {
+    find_in(load_lines(None), id_prefix)
+}
+
+fn find_in(lines: Vec<String>, id_prefix: &str) -> Result<(LineNumber, Record), KprError> {
+    let mut matches: Vec<(LineNumber, Record)> = replay(lines)
+        .into_iter()
+        .filter(|(_, record)| record.id.starts_with(id_prefix))
+        .collect();
+
+    match matches.len() {
+        0 => Err(KprError::new(&format!("No message has an ID starting with '{id_prefix}'"))),
+        1 => Ok(matches.remove(0)),
+        n => Err(KprError::new(&format!("{n} messages have an ID starting with '{id_prefix}', please give more of the ID"))),
+    }
+}
+
+/// Locks the store for as long as `f` runs, passing it the locked file and the store's lines.
+/// Anything else which writes to the store waits until `f` is done.
+pub fn with_lock<T, E, F>(f: F) -> Result<T, E>
+    where E: From<std::io::Error>,
+          F: FnOnce(&File, Vec<String>) -> Result<T, E> {
+        let filepath = full_path(STORE_FILENAME);
+        loop {
+            let file = open_or_create(&filepath, true)?;
+            let _lock_guard = LockGuard::new(&file)?;
+
+            // `compact` and `migrate` rename a new store into place, so the file we waited on
+            // may have been replaced by the time we hold its lock. If so, lock the new one.
+            if !is_same_file(&file, &filepath)? {
+                continue;
+            }
+
+            let mut bytes = Vec::new();
+            file.try_clone()?.read_to_end(&mut bytes)?;
+            let lines = lines_of(&bytes);
+            check_writable(&lines)?;
+
+            // The file will be unlocked when _lock_guard goes out of scope, even if an error occurs.
+            return f(&file, lines);
+        }
+}
+
+/// Whether `file` is still the file at `filepath`, rather than one which has been replaced.
+#[cfg(unix)]
+fn is_same_file(file: &File, filepath: &Path) -> Result<bool, std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let current = match std::fs::metadata(filepath) {
+        Ok(current) => current,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let open = file.metadata()?;
+    Ok(open.dev() == current.dev() && open.ino() == current.ino())
+}
+
+/// Files which are open can't be replaced on other platforms.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _filepath: &Path) -> Result<bool, std::io::Error> {
+    Ok(true)
+}
+
+/// Appends an entry to the store and returns its line number.
+/// `update_index` is called with the line number while the store is still locked,
+/// so that the store and index change together.
+pub fn write<E, F>(entry: &Entry, update_index: F) -> Result<LineNumber, E>
+    where E: From<std::io::Error>,
+          F: FnOnce(LineNumber) -> Result<(), E> {
+        with_lock(|file, lines| append_and_index(file, &lines, entry, update_index))
+}
+
+/// Appends the edit or deletion which `change` makes of the note `id_prefix` identifies, and
+/// returns its line number. The note is looked up once the store is locked, so nothing can
+/// change or delete it in between. `update_index` is called as for `write`.
+pub fn change<E, C, F>(id_prefix: &str, change: C, update_index: F) -> Result<LineNumber, E>
+    where E: From<std::io::Error> + From<KprError>,
+          C: FnOnce(Record) -> Result<Entry, E>,
+          F: FnOnce(LineNumber) -> Result<(), E> {
+        with_lock(|file, lines| {
+            let (_, note) = find_in(lines.clone(), id_prefix)?;
+            append_and_index(file, &lines, &change(note)?, update_index)
+        })
+}
+
+/// Appends `entry` to the locked store and then calls `update_index`. If the index can't be
+/// updated, the store is cut back to how it was, so that a failed write leaves neither changed.
+fn append_and_index<E, F>(file: &File, lines: &[String], entry: &Entry, update_index: F) -> Result<LineNumber, E>
+    where E: From<std::io::Error>,
+          F: FnOnce(LineNumber) -> Result<(), E> {
+        let length = file.metadata()?.len();
+        let line_number = append(file, lines, entry)?;
+
+        if let Err(e) = update_index(line_number) {
+            file.set_len(length)?;
+            return Err(e);
+        }
+        Ok(line_number)
+}
+
+/// Writes `entry` at the end of the locked store, which holds `lines`, and returns its line number.
+fn append(file: &File, lines: &[String], entry: &Entry) -> Result<LineNumber, std::io::Error> {
+    let mut writer = file;
+    if lines.is_empty() {
+        writeln!(writer, "{}", header())?;
+    }
+    let line_number = lines.len().max(1) as LineNumber;
+
+    // IDs are checked here, under the lock, so two notes can never share one.
+    let unique;
+    let entry = match entry {
+        Entry::Note(record) => {
+            let taken: HashSet<String> = lines
+                .iter()
+                .filter_map(|line| Entry::from_store(line))
+                .map(|entry| entry.id().to_string())
+                .collect();
+            unique = Entry::Note(record.with_unique_id(&taken));
+            &unique
+        },
+        entry => entry,
+    };
+
+    let store_line = entry.to_store();
+    writeln!(writer, "{store_line}")?;
+    Ok(line_number)
+}
+
+/// The first line of every store written in the current format.
+pub fn header() -> String {
+    format!("{HEADER_PREFIX}{FORMAT_VERSION}")
+}
 
-    let _lock_guard = LockGuard::new(&file)?;
+/// The format version of a store. Stores without a header are version 1.
+pub fn format_version(lines: &[String]) -> u32 {
+    lines
+        .first()
+        .and_then(|line| line.strip_prefix(HEADER_PREFIX))
+        .and_then(|version| version.trim().parse().ok())
+        .unwrap_or(1)
+}
 
-    let reader = BufReader::new(file.try_clone()?);
-    let line_count = reader.lines().count() as u16;
+/// Refuses to touch a store written by a newer kpr, which may have entries this one would mangle.
+pub fn check_writable(lines: &[String]) -> Result<(), std::io::Error> {
+    let version = format_version(lines);
+    if version > FORMAT_VERSION {
+        return Err(std::io::Error::other(format!(
+            "{STORE_FILENAME} is in format {version}, but this kpr only understands up to format {FORMAT_VERSION}. Please upgrade kpr."
+        )));
+    }
+    Ok(())
+}
 
-    let store_line = record.to_store();
-    writeln!(&mut file.try_clone()?, "{store_line}")?;
+/// Rewrites every line of a store in the current format, under a header.
+/// Lines which aren't entries are kept as they are.
+pub fn migrate_lines(lines: Vec<String>) -> Vec<String> {
+    let entries = lines
+        .into_iter()
+        .skip_while(|line| line.starts_with(HEADER_PREFIX))
+        .map(|line| match Entry::from_store(&line) {
+            Some(entry) => entry.to_store(),
+            None => line,
+        });
 
-    // The file will be unlocked when _lock_guard goes out of scope, even if an error occurs.
-    Ok(line_count)
+    std::iter::once(header()).chain(entries).collect()
+}
+
+/// Locks the store and replaces its lines with `transform(lines)`, unless that returns `None`.
+/// The new lines are written to a temporary file which is renamed over the store, so a crash or
+/// a full disk part way through leaves the old store whole.
+/// `rebuild_index` is called once the new lines are written, while the store is still locked.
+fn rewrite<T, E, F>(transform: T, rebuild_index: F) -> Result<bool, E>
+    where E: From<std::io::Error>,
+          T: FnOnce(Vec<String>) -> Result<Option<Vec<String>>, std::io::Error>,
+          F: FnOnce() -> Result<(), E> {
+        with_lock(|_, lines| {
+            let new_lines = match transform(lines)? {
+                Some(new_lines) => new_lines,
+                None => return Ok(false),
+            };
+
+            // Anyone waiting on the lock notices the store was replaced, and locks the new one.
+            let contents: String = new_lines.iter().map(|line| format!("{line}\n")).collect();
+            write_atomically(&full_path(STORE_FILENAME), |file| file.write_all(contents.as_bytes()))?;
+
+            rebuild_index()?;
+            Ok(true)
+        })
+}
+
+/// Rewrites the store so that it holds only live notes, with edits folded in and deletions dropped.
+/// `rebuild_index` is called once the store is rewritten, while it is still locked.
+/// Returns the number of entries removed.
+pub fn compact<E, F>(rebuild_index: F) -> Result<usize, E>
+    where E: From<std::io::Error>,
+          F: FnOnce() -> Result<(), E> {
+        let mut removed = 0;
+
+        rewrite(|lines| {
+            let entry_count = lines.iter().filter(|line| Entry::from_store(line).is_some()).count();
+            let notes: Vec<String> = replay(lines)
+                .into_iter()
+                .map(|(_, record)| record.to_store())
+                .collect();
+
+            removed = entry_count - notes.len();
+            Ok(Some(std::iter::once(header()).chain(notes).collect()))
+        }, rebuild_index)?;
+
+        Ok(removed)
+}
+
+/// Upgrades the store to the current format, first copying it to a backup file.
+/// Returns the path of the backup, or `None` if the store was already up to date.
+pub fn migrate<E, F>(rebuild_index: F) -> Result<Option<PathBuf>, E>
+    where E: From<std::io::Error>,
+          F: FnOnce() -> Result<(), E> {
+        let mut backup = None;
+
+        rewrite(|lines| {
+            let version = format_version(&lines);
+            if version == FORMAT_VERSION || lines.is_empty() {
+                return Ok(None);
+            }
+
+            // Copied rather than written from `lines`, so the backup is exactly the old store.
+            let backup_path = full_path(format!("{STORE_FILENAME}.v{version}.bak"));
+            std::fs::copy(full_path(STORE_FILENAME), &backup_path)?;
+            backup = Some(backup_path);
+
+            Ok(Some(migrate_lines(lines)))
+        }, rebuild_index)?;
+
+        Ok(backup)
+}
+
+/// Writes a file by writing a temporary sibling and renaming it into place,
+/// so readers only ever see the old or the new contents.
+pub fn write_atomically<F>(filepath: &Path, write_contents: F) -> Result<(), std::io::Error>
+    where F: FnOnce(&mut File) -> Result<(), std::io::Error> {
+        // Named per process, in case two processes write the same file at once.
+        let mut tmp_path = filepath.as_os_str().to_owned();
+        tmp_path.push(format!(".{}.tmp", std::process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = open_or_create(&tmp_path, false)?;
+        write_contents(&mut file)?;
+        file.sync_all()?;
+
+        std::fs::rename(tmp_path, filepath)
 }
 
 // Unit tests for the store module
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::BufReader;
 
     #[test]
     fn test_open_or_create_creates_file_if_it_does_not_exist() {
@@ -97,11 +527,11 @@ mod tests {
 
         let _ = std::fs::remove_file(&filepath);
 
-        assert_eq!(filepath.exists(), false);
+        assert!(!filepath.exists());
 
         open_or_create(&filepath, false).expect("Could not open store file");
 
-        assert_eq!(filepath.exists(), true);
+        assert!(filepath.exists());
     }
 
     #[test]
@@ -110,11 +540,11 @@ mod tests {
 
         let _ = std::fs::remove_file(&filepath);
 
-        assert_eq!(filepath.exists(), false);
+        assert!(!filepath.exists());
 
         open_or_create(&filepath, false).expect("Could not open store file");
 
-        assert_eq!(filepath.exists(), true);
+        assert!(filepath.exists());
 
         // Open the file again and write some text to it.
         let mut file = open_or_create(&filepath, false).expect("Could not open store file");
@@ -133,11 +563,11 @@ mod tests {
 
         let _ = std::fs::remove_file(&filepath);
 
-        assert_eq!(filepath.exists(), false);
+        assert!(!filepath.exists());
 
         open_or_create(&filepath, false).expect("Could not open store file");
 
-        assert_eq!(filepath.exists(), true);
+        assert!(filepath.exists());
 
         // Open the file again and write some text to it.
         let mut file = open_or_create(&filepath, true).expect("Could not open store file");
@@ -149,4 +579,138 @@ mod tests {
         let line_count =  reader.lines().count();
         assert_eq!(line_count, 1);
     }
-}
\ No newline at end of file
+
+    fn lines(entries: &[Entry]) -> Vec<String> {
+        entries.iter().map(Entry::to_store).collect()
+    }
+
+    #[test]
+    fn test_replay_applies_edits_and_deletions() {
+        let first = Record::create("frist note".to_string());
+        let second = Record::create("second note".to_string());
+        let fix = Record::new(first.id.clone(), first.timestamp, "first note".to_string());
+        let delete = Entry::Delete { id: second.id.clone(), timestamp: second.timestamp };
+
+        let records = replay(lines(&[Entry::Note(first), Entry::Note(second), Entry::Edit(fix), delete]));
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, 0);
+        assert_eq!(records[0].1.message, "first note");
+    }
+
+    #[test]
+    fn test_format_version() {
+        assert_eq!(format_version(&[]), 1);
+        assert_eq!(format_version(&["1680917693908: message two".to_string()]), 1);
+        assert_eq!(format_version(&[header()]), FORMAT_VERSION);
+        assert!(check_writable(&[header()]).is_ok());
+        assert!(check_writable(&[format!("{HEADER_PREFIX}{}", FORMAT_VERSION + 1)]).is_err());
+    }
+
+    #[test]
+    fn test_migrate_lines() {
+        let lines = vec![
+            r"1680917693908: C:\new\folder".to_string(),
+            "not an entry".to_string(),
+        ];
+        let migrated = migrate_lines(lines);
+
+        assert_eq!(migrated.len(), 3);
+        assert_eq!(migrated[0], header());
+        assert!(migrated[1].ends_with(r": C:\\new\\folder"));
+        assert_eq!(migrated[2], "not an entry");
+
+        // The note keeps its ID and message.
+        let before = replay(vec![r"1680917693908: C:\new\folder".to_string()]);
+        let after = replay(migrated.clone());
+        assert_eq!(after[0].1.id, before[0].1.id);
+        assert_eq!(after[0].1.message, before[0].1.message);
+
+        // Migrating again changes nothing.
+        assert_eq!(migrate_lines(migrated.clone()), migrated);
+    }
+
+    #[test]
+    fn test_fingerprint_recognises_appended_lines() {
+        let store = b"# kpr store format 2\n1680917693908 0a1b2c3d: first\n";
+        let fingerprint = Fingerprint::of(store);
+        assert_eq!(fingerprint.lines, 2);
+
+        let mut appended = store.to_vec();
+        appended.extend(b"1680917698382 4e5f6a7b: second\n");
+        assert!(fingerprint.is_prefix_of(&appended));
+        assert_ne!(Fingerprint::of(&appended), fingerprint);
+
+        let edited = b"# kpr store format 2\n1680917693908 0a1b2c3d: frist\n1680917698382 4e5f6a7b: second\n";
+        assert!(!fingerprint.is_prefix_of(edited));
+        assert!(!fingerprint.is_prefix_of(&store[..10]));
+        assert!(Fingerprint::default().is_prefix_of(store));
+    }
+
+    #[test]
+    fn test_fingerprint_notices_edits_far_from_the_end() {
+        let mut store = b"# kpr store format 2\n1680917693908 0a1b2c3d: zebra crossing notes\n".to_vec();
+        for i in 0..200 {
+            store.extend(format!("16809176{i:05} 0a1b{i:04}: padding the store out past a few KiB\n").bytes());
+        }
+        let fingerprint = Fingerprint::of(&store);
+
+        let edited = String::from_utf8(store.clone()).expect("Store is unicode").replacen("zebra", "koala", 1);
+        assert_eq!(edited.len(), store.len());
+        assert_ne!(Fingerprint::of(edited.as_bytes()), fingerprint);
+        assert!(!fingerprint.is_prefix_of(edited.as_bytes()));
+    }
+
+    #[test]
+    fn test_fingerprint_knows_whether_the_store_is_in_date_order() {
+        let store = "# kpr store format 2\n1680917693908 0a1b2c3d: first\n1680917698382 0a1b2c3d edit: fixed\n";
+        assert!(Fingerprint::of(store.as_bytes()).in_date_order);
+        assert!(Fingerprint::default().in_date_order);
+
+        let merged = format!("{store}1680917690000 4e5f6a7b: from another machine\n");
+        assert!(!Fingerprint::of(merged.as_bytes()).in_date_order);
+    }
+
+    #[test]
+    fn test_write_atomically_replaces_contents() {
+        let filepath = full_path("test_write_atomically_replaces_contents.txt");
+        std::fs::write(&filepath, "old contents").expect("Could not write file");
+
+        write_atomically(&filepath, |file| write!(file, "new contents")).expect("Could not write file");
+
+        assert_eq!(std::fs::read_to_string(&filepath).expect("Could not read file"), "new contents");
+    }
+
+    #[test]
+    fn test_failed_index_updates_undo_the_write() {
+        let filepath = full_path("test_failed_index_updates_undo_the_write.txt");
+        let kept = format!("{}\n{}\n", header(), Record::create("kept".to_string()).to_store());
+        std::fs::write(&filepath, &kept).expect("Could not write file");
+
+        let file = open_or_create(&filepath, true).expect("Could not open file");
+        let lines: Vec<String> = kept.lines().map(str::to_string).collect();
+        let entry = Entry::Note(Record::create("lost".to_string()));
+
+        let written = append_and_index(&file, &lines, &entry, |_| Err(std::io::Error::other("Index is read-only")));
+        assert!(written.is_err());
+        assert_eq!(std::fs::read_to_string(&filepath).expect("Could not read file"), kept);
+
+        let written: Result<LineNumber, std::io::Error> = append_and_index(&file, &lines, &entry, |_| Ok(()));
+        assert_eq!(written.expect("Could not write"), 2);
+        assert!(std::fs::read_to_string(&filepath).expect("Could not read file").ends_with("lost\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replaced_files_are_noticed() {
+        let filepath = full_path("test_replaced_files_are_noticed.txt");
+        std::fs::write(&filepath, "old contents").expect("Could not write file");
+        let file = open_or_create(&filepath, true).expect("Could not open file");
+        assert!(is_same_file(&file, &filepath).expect("Could not compare files"));
+
+        let replacement = full_path("test_replaced_files_are_noticed.txt.tmp");
+        std::fs::write(&replacement, "new contents").expect("Could not write file");
+        std::fs::rename(&replacement, &filepath).expect("Could not replace file");
+        assert!(!is_same_file(&file, &filepath).expect("Could not compare files"));
+    }
+}
@@ -5,34 +5,39 @@ extern crate unicode_segmentation;
 use unicode_segmentation::UnicodeSegmentation;
 use colored::Colorize;
 
-pub fn make_table(rows: &[(String, String)]) -> Vec<String> {
-    let timestamp_col_width = max_timestamp_width(rows);
-    
+use crate::config;
+
+pub fn make_table(rows: &[(String, String, String)]) -> Vec<String> {
+    let id_col_width = max_width(rows.iter().map(|(id, _, _)| id));
+    let timestamp_col_width = max_width(rows.iter().map(|(_, timestamp, _)| timestamp));
+    let indent = id_col_width + 2 + timestamp_col_width;
+    let width = config::settings().wrap_width;
+
     rows
-        .into_iter()
-        .map(|(timestamp, message)| format_row(timestamp.to_string(), message.to_string(), timestamp_col_width))
-        .map(|(timestamp, message)| (timestamp.bright_black().to_string(), message.to_string()))
-        .map(|(timestamp, message)| format!("{timestamp}  {message}"))
+        .iter()
+        .map(|(id, timestamp, message)| (id, format_row(timestamp.to_string(), message.to_string(), timestamp_col_width, indent, width)))
+        .map(|(id, (timestamp, message))| (id.yellow().to_string(), timestamp.bright_black().to_string(), message))
+        .map(|(id, timestamp, message)| format!("{id}  {timestamp}  {message}"))
         .collect()
 }
 
 
-fn format_row(timestamp: String, message: String, timestamp_width: usize) -> (String, String) {
+fn format_row(timestamp: String, message: String, timestamp_width: usize, indent: usize, width: usize) -> (String, String) {
     let timestamp = format!("{timestamp:>timestamp_width$}", timestamp=timestamp, timestamp_width=timestamp_width);
 
-    let message_rows = wrap(&message, 80);
+    let message_rows = wrap(&message, width);
     let message = &message_rows[0];
     if message_rows.len() == 1 {
         return (timestamp, message.to_string());
     }
 
-    let rows = format_row_parts(message_rows, timestamp_width);
+    let rows = format_row_parts(message_rows, indent);
 
     (timestamp, rows)
 }
 
 
-fn format_row_parts(message_rows: Vec<Cow<str>>, timestamp_width: usize) -> String {
+fn format_row_parts(message_rows: Vec<Cow<str>>, indent: usize) -> String {
     
     let mut parts = message_rows.into_iter();
     let first_part = match parts.next() {
@@ -40,19 +45,41 @@ fn format_row_parts(message_rows: Vec<Cow<str>>, timestamp_width: usize) -> Stri
         None => return String::new(),
     };
 
-    let padding = " ".repeat(timestamp_width + 2);
+    let padding = " ".repeat(indent + 2);
     let subsequent_parts = parts
-        .map(|msg| format!("{padding}{msg}"));
+        .map(|msg| if msg.is_empty() { String::new() } else { format!("{padding}{msg}") });
 
     let all_rows: Vec<_> = first_part.chain(subsequent_parts).collect();
 
     all_rows.join("\n")
 }
 
-fn max_timestamp_width(lines: &[(String, String)]) -> usize {
-    lines
-        .into_iter()
-        .map(|(timestamp, _)| timestamp.graphemes(true).count())
+fn max_width<'a>(column: impl Iterator<Item=&'a String>) -> usize {
+    column
+        .map(|cell| cell.graphemes(true).count())
         .max()
         .unwrap_or(0)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_newlines_get_hanging_indent() {
+        let (_, message) = format_row("now".to_string(), "first\n  second\n\nfourth".to_string(), 3, 10, 80);
+
+        assert_eq!(message, "first\n              second\n\n            fourth");
+    }
+
+    #[test]
+    fn test_long_lines_wrap_with_hanging_indent() {
+        let long_line = "word ".repeat(30);
+        let (_, message) = format_row("now".to_string(), long_line, 3, 0, 80);
+
+        let rows: Vec<&str> = message.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[1].starts_with("  word"));
+    }
+}
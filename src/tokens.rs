@@ -0,0 +1,146 @@
+use std::ops::Range;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::query::WILDCARD;
+
+/// A word of some text, and where it is in the text, in bytes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub span: Range<usize>,
+}
+
+/// Splits text into words at Unicode word boundaries, leaving out spaces and punctuation, so
+/// `deploy,` is `deploy` and `db-1` is `db` and `1`. Scripts written without spaces, like
+/// Chinese and Japanese, are split into overlapping pairs of characters instead, so that
+/// `東京都` is `東京` and `京都`.
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    tokens_of(text, false)
+}
+
+/// Splits a word of a search like `tokenize`, but keeps wildcards with the words they touch,
+/// so `INC-20*` is `INC` and `20*`. A wildcard on its own only stays if it is the whole word,
+/// so `INC-*` is just `INC`. A single character of a script without spaces is only indexed in
+/// the pairs it is part of, so it becomes a pattern matching those: `京` is `*京*`.
+pub fn search_words(word: &str) -> Vec<String> {
+    let mut words: Vec<String> = tokens_of(word, true)
+        .into_iter()
+        .map(|token| match token.text.chars().collect::<Vec<char>>()[..] {
+            [c] if is_spaceless(c) => format!("{WILDCARD}{c}{WILDCARD}"),
+            _ => token.text.to_string(),
+        })
+        .collect();
+    if words.len() > 1 {
+        words.retain(|word| !word.chars().all(|c| c == WILDCARD));
+    }
+    words
+}
+
+fn tokens_of(text: &str, keep_wildcards: bool) -> Vec<Token<'_>> {
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    let mut spaceless: Option<Range<usize>> = None;
+    let mut after_wildcard = false;
+
+    for (start, segment) in text.split_word_bound_indices() {
+        let end = start + segment.len();
+
+        if segment.chars().all(is_spaceless) {
+            spaceless = match spaceless {
+                Some(run) if run.end == start => Some(run.start..end),
+                run => {
+                    spans.extend(run.map(|run| bigrams(text, run)).unwrap_or_default());
+                    Some(start..end)
+                },
+            };
+            continue;
+        }
+        spans.extend(spaceless.take().map(|run| bigrams(text, run)).unwrap_or_default());
+
+        let is_wildcard = keep_wildcards && segment.chars().all(|c| c == WILDCARD);
+        let is_word = segment.chars().any(char::is_alphanumeric);
+        let joins_previous = is_wildcard || (is_word && after_wildcard);
+
+        if is_wildcard || is_word {
+            match spans.last_mut() {
+                Some(previous) if joins_previous && previous.end == start => previous.end = end,
+                _ => spans.push(start..end),
+            }
+        }
+        after_wildcard = is_wildcard;
+    }
+    spans.extend(spaceless.map(|run| bigrams(text, run)).unwrap_or_default());
+
+    spans.into_iter().map(|span| Token { text: &text[span.clone()], span }).collect()
+}
+
+/// Each pair of neighbouring characters in `run`, or the character if there is only one.
+fn bigrams(text: &str, run: Range<usize>) -> Vec<Range<usize>> {
+    let mut starts: Vec<usize> = text[run.clone()].char_indices().map(|(i, _)| run.start + i).collect();
+    if starts.len() == 1 {
+        return vec![run];
+    }
+    starts.push(run.end);
+    starts.windows(3).map(|window| window[0]..window[2]).collect()
+}
+
+/// Whether `c` is from a script written without spaces between words.
+fn is_spaceless(c: char) -> bool {
+    matches!(c,
+        '\u{0E00}'..='\u{0EFF}'       // Thai and Lao
+        | '\u{1000}'..='\u{109F}'     // Myanmar
+        | '\u{1780}'..='\u{17FF}'     // Khmer
+        | '\u{3040}'..='\u{30FF}'     // Hiragana and Katakana
+        | '\u{31F0}'..='\u{31FF}'
+        | '\u{3400}'..='\u{4DBF}'     // CJK ideographs
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF66}'..='\u{FF9F}'     // Half width Katakana
+        | '\u{20000}'..='\u{2FA1F}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(text: &str) -> Vec<&str> {
+        tokenize(text).into_iter().map(|token| token.text).collect()
+    }
+
+    #[test]
+    fn test_punctuation_is_left_out() {
+        assert_eq!(texts("Deploy, then (maybe) roll back!"), vec!["Deploy", "then", "maybe", "roll", "back"]);
+        assert_eq!(texts("db-1 at 10.0.3.17 ran foo_bar()"), vec!["db", "1", "at", "10.0.3.17", "ran", "foo_bar"]);
+        assert!(texts(" -- ... ").is_empty());
+    }
+
+    #[test]
+    fn test_spans_point_into_the_text() {
+        let text = "héllo, wörld";
+        for token in tokenize(text) {
+            assert_eq!(&text[token.span], token.text);
+        }
+        assert_eq!(tokenize(text)[1].span, 8..14);
+    }
+
+    #[test]
+    fn test_scripts_without_spaces_are_split_into_bigrams() {
+        assert_eq!(texts("東京都に行く"), vec!["東京", "京都", "都に", "に行", "行く"]);
+        assert_eq!(texts("猫 and 犬"), vec!["猫", "and", "犬"]);
+        assert_eq!(texts("日本語text"), vec!["日本", "本語", "text"]);
+    }
+
+    #[test]
+    fn test_search_words_keep_wildcards() {
+        assert_eq!(search_words("INC-20*"), vec!["INC", "20*"]);
+        assert_eq!(search_words("*back*"), vec!["*back*"]);
+        assert_eq!(search_words("d*k"), vec!["d*k"]);
+        assert_eq!(search_words("*"), vec!["*"]);
+        assert_eq!(search_words("INC-*"), vec!["INC"]);
+        assert_eq!(search_words("deploy,"), vec!["deploy"]);
+        assert_eq!(search_words("京"), vec!["*京*"]);
+        assert_eq!(search_words("京都"), vec!["京都"]);
+        assert!(search_words("-").is_empty());
+    }
+}